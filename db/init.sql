@@ -9,6 +9,17 @@ CREATE TABLE events
     PRIMARY KEY (aggregate_type, aggregate_id, sequence)
 );
 
+-- the latest snapshot of each aggregate, as it stood after event last_sequence
+CREATE TABLE snapshots
+(
+    aggregate_type text                              NOT NULL,
+    aggregate_id   text                              NOT NULL,
+    last_sequence  bigint CHECK (last_sequence >= 0) NOT NULL,
+    payload        jsonb                             NOT NULL,
+    timestamp      timestamp with time zone DEFAULT (CURRENT_TIMESTAMP),
+    PRIMARY KEY (aggregate_type, aggregate_id)
+);
+
 CREATE TABLE ipam_query
 (
     query_instance_id text                        NOT NULL,
@@ -17,5 +28,29 @@ CREATE TABLE ipam_query
     PRIMARY KEY (query_instance_id)
 );
 
+CREATE TABLE ipam_cidrs_query
+(
+    query_instance_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           jsonb                       NOT NULL,
+    PRIMARY KEY (query_instance_id)
+);
+
+CREATE TABLE ipam_utilization_query
+(
+    query_instance_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           jsonb                       NOT NULL,
+    PRIMARY KEY (query_instance_id)
+);
+
+CREATE TABLE ipam_history_query
+(
+    query_instance_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           jsonb                       NOT NULL,
+    PRIMARY KEY (query_instance_id)
+);
+
 CREATE USER ipam_user WITH ENCRYPTED PASSWORD 'secret_saucey';
 GRANT ALL PRIVILEGES ON DATABASE postgres TO ipam_user;
@@ -0,0 +1,256 @@
+//! Typed attribute values for CidrEntries.
+//!
+//! Each key appears at most once on an entry. Values are plain JSON, with an
+//! object holding only `ip` read as a reference to an address or network.
+//!
+//! ```text
+//! {
+//!   "env": "prod",
+//!   "vlan": 100,
+//!   "routed": true,
+//!   "dns": ["10.0.0.2", "10.0.0.3"],
+//!   "site": {"region": "ap-southeast-2", "rack": 12},
+//!   "gateway": {"ip": "10.0.0.1"}
+//! }
+//! ```
+//!
+//! Earlier entries stored their attributes as a list of `{"key": .., "value": ..}`
+//! labels, which still loads.
+
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Iter;
+use std::fmt;
+use std::iter::FromIterator;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::ipam_model::Label;
+
+#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum AttributeValue {
+    Bool(bool),
+    Number(serde_json::Number),
+    Ip(IpRef),
+    String(String),
+    List(Vec<AttributeValue>),
+    Map(BTreeMap<String, AttributeValue>),
+}
+
+/// A reference to an address or network, `{"ip": "10.0.0.1"}`
+#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct IpRef {
+    #[serde(deserialize_with = "network_or_address")]
+    pub ip: IpNetwork,
+}
+
+fn network_or_address<'de, D: Deserializer<'de>>(d: D) -> Result<IpNetwork, D::Error> {
+    let s = String::deserialize(d)?;
+    IpNetwork::from_str(&s)
+        .or_else(|_| IpAddr::from_str(&s).map(IpNetwork::from))
+        .map_err(serde::de::Error::custom)
+}
+
+impl Default for AttributeValue {
+    fn default() -> Self {
+        AttributeValue::String(String::new())
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(s: &str) -> Self {
+        AttributeValue::String(String::from(s))
+    }
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeValue::Bool(b) => write!(f, "{}", b),
+            AttributeValue::Number(n) => write!(f, "{}", n),
+            AttributeValue::Ip(r) => write!(f, "{}", r.ip),
+            AttributeValue::String(s) => write!(f, "{}", s),
+            other => write!(f, "{}", serde_json::to_string(other).map_err(|_| fmt::Error)?),
+        }
+    }
+}
+
+impl AttributeValue {
+    /// Whether the value equals `text`, read as the value's own type.
+    /// Any item of a list will do.
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            AttributeValue::Bool(b) => text.parse::<bool>().map_or(false, |t| t == *b),
+            AttributeValue::Number(n) => match (n.as_f64(), text.parse::<f64>()) {
+                (Some(n), Ok(t)) => (n - t).abs() < f64::EPSILON,
+                _ => false,
+            },
+            AttributeValue::Ip(r) => IpNetwork::from_str(text)
+                .or_else(|_| IpAddr::from_str(text).map(IpNetwork::from))
+                .map_or(false, |t| t == r.ip),
+            AttributeValue::String(s) => s == text,
+            AttributeValue::List(items) => items.iter().any(|i| i.matches(text)),
+            AttributeValue::Map(_) => false,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            AttributeValue::Number(n) => n.as_f64(),
+            _ => None,
+        }
+    }
+}
+
+/// The attributes of an entry, one value per key
+#[derive(Eq, PartialEq, Hash, Debug, Serialize, Clone, Default)]
+#[serde(transparent)]
+pub struct Attributes(BTreeMap<String, AttributeValue>);
+
+impl Attributes {
+    pub fn get(&self, key: &str) -> Option<&AttributeValue> {
+        self.0.get(key)
+    }
+
+    /// The value at a dotted path into nested maps, `site.rack`. A key
+    /// holding dots of its own is matched whole first.
+    pub fn lookup(&self, path: &str) -> Option<&AttributeValue> {
+        if let Some(v) = self.0.get(path) {
+            return Some(v);
+        }
+        let mut parts = path.split('.');
+        let mut current = self.0.get(parts.next()?)?;
+        for part in parts {
+            current = match current {
+                AttributeValue::Map(m) => m.get(part)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    pub fn contains(&self, label: &Label) -> bool {
+        self.0.get(&label.key) == Some(&label.value)
+    }
+
+    /// Set the value for the label's key, handing back whatever it replaced
+    pub fn insert(&mut self, label: Label) -> Option<AttributeValue> {
+        self.0.insert(label.key, label.value)
+    }
+
+    /// Remove the label, only when the entry holds that exact value for the key
+    pub fn remove(&mut self, label: &Label) -> bool {
+        if self.contains(label) {
+            self.0.remove(&label.key);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn remove_key(&mut self, key: &str) -> Option<AttributeValue> {
+        self.0.remove(key)
+    }
+
+    pub fn iter(&self) -> Iter<'_, String, AttributeValue> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Labels with the same key are gathered into a list, so nothing is lost
+/// from attributes stored before keys were unique
+impl FromIterator<Label> for Attributes {
+    fn from_iter<I: IntoIterator<Item = Label>>(labels: I) -> Self {
+        let mut found: BTreeMap<String, AttributeValue> = BTreeMap::new();
+        for label in labels {
+            match found.remove(&label.key) {
+                None => found.insert(label.key, label.value),
+                Some(AttributeValue::List(mut items)) => {
+                    if !items.contains(&label.value) {
+                        items.push(label.value);
+                    }
+                    found.insert(label.key, AttributeValue::List(items))
+                }
+                Some(existing) if existing == label.value => found.insert(label.key, existing),
+                Some(existing) => found.insert(label.key, AttributeValue::List(vec![existing, label.value])),
+            };
+        }
+        Attributes(found)
+    }
+}
+
+impl<'de> Deserialize<'de> for Attributes {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Map(BTreeMap<String, AttributeValue>),
+            Labels(Vec<Label>),
+        }
+        Ok(match Stored::deserialize(d)? {
+            Stored::Map(m) => Attributes(m),
+            Stored::Labels(labels) => labels.into_iter().collect(),
+        })
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_typed_values() {
+        let attributes: Attributes = serde_json::from_str(r#"{
+            "env": "prod", "vlan": 100, "routed": true, "dns": ["10.0.0.2", "10.0.0.3"],
+            "site": {"region": "syd", "rack": 12}, "gateway": {"ip": "10.0.0.1"}
+        }"#).unwrap();
+
+        assert_eq!(attributes.get("env"), Some(&AttributeValue::from("prod")));
+        assert_eq!(attributes.get("vlan").and_then(|v| v.as_f64()), Some(100.0));
+        assert_eq!(attributes.get("routed"), Some(&AttributeValue::Bool(true)));
+        assert!(attributes.get("dns").unwrap().matches("10.0.0.3"));
+        assert!(matches!(attributes.get("gateway"), Some(AttributeValue::Ip(_))));
+        assert!(attributes.get("gateway").unwrap().matches("10.0.0.1/32"));
+        assert_eq!(attributes.lookup("site.rack").and_then(|v| v.as_f64()), Some(12.0));
+        assert_eq!(attributes.lookup("site.nope"), None);
+
+        // and back again, unchanged
+        let json = serde_json::to_string(&attributes).unwrap();
+        assert_eq!(serde_json::from_str::<Attributes>(&json).unwrap(), attributes);
+    }
+
+    #[test]
+    fn test_keys_are_unique() {
+        let mut attributes = Attributes::default();
+        attributes.insert(Label::new("env", "dev"));
+        assert_eq!(attributes.insert(Label::new("env", "prod")), Some(AttributeValue::from("dev")));
+        assert_eq!(attributes.len(), 1);
+
+        assert!(!attributes.remove(&Label::new("env", "dev")));
+        assert!(attributes.remove(&Label::new("env", "prod")));
+        assert!(attributes.is_empty());
+    }
+
+    #[test]
+    fn test_stored_labels_still_load() {
+        let attributes: Attributes = serde_json::from_str(r#"[
+            {"key": "env", "value": "prod"}, {"key": "dns", "value": "a"}, {"key": "dns", "value": "b"}
+        ]"#).unwrap();
+
+        assert_eq!(attributes.get("env"), Some(&AttributeValue::from("prod")));
+        assert_eq!(attributes.get("dns"), Some(&AttributeValue::List(vec![AttributeValue::from("a"), AttributeValue::from("b")])));
+    }
+}
@@ -1,51 +1,20 @@
-use cqrs_es::{AggregateError, Command};
+use cqrs_es::{AggregateError, Command, DomainEvent};
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashSet;
 // use std::convert::TryFrom;
 use std::str::FromStr;
 use ipnetwork::IpNetwork;
 use uuid::Uuid;
 
-use crate::ipam_model::{Ipam, IPProtocolFamily, Label, IpamConfig, CidrEntry};
-use crate::events::{IpamEvent, IpamCreated, CidrEntryAdded};
-// use crate::error::IpamError;
-
-// #[derive(Serialize, Deserialize)]
-// pub struct AddAttributeToCidrEntry {
-//     pub cidr: String,
-//     pub attribute: Label,
-// }
-
-// #[derive(Serialize, Deserialize)]
-// pub struct AddAttributeToCidrById {
-//     pub id: String,
-//     pub attribute: Label,
-// }
-
-// #[derive(Serialize, Deserialize)]
-// pub struct RemoveAttributeByKeyFromCidr {
-//     pub cidr: String,
-//     pub key: String,
-// }
-
-// #[derive(Serialize, Deserialize)]
-// pub struct RemoveAttributeByKeyFromCidrById {
-//     pub id: String,
-//     pub key: String,
-// }
-
-// #[derive(Serialize, Deserialize)]
-// pub struct RemoveAttributeFromCidr {
-//     pub cidr: String,
-//     pub attribute: Label,
-// }
-
-// #[derive(Serialize, Deserialize)]
-// pub struct RemoveAttributeFromCidrById {
-//     pub id: String,
-//     pub attribute: Label,
-// }
+use crate::ipam_model::{Ipam, IPProtocolFamily, Label, IpamConfig, CidrEntry, CidrEntryResult, CidrId, AllocationStrategy,
+    AttributeCarry, CanonicalPolicy, ChildPolicy, EntryState, Finder, SysRef, now_secs, subnets_of, supernet_of};
+use crate::events::{IpamEvent, IpamCreated, CidrEntryAdded, CidrEntryReparented, CidrEntryReleased,
+    CidrAttributeAdded, CidrAttributeRemoved, CidrEntriesPaired, CidrEntriesUnpaired, AttributeSchemaChanged,
+    CidrEntryStateChanged, CidrBlockQuarantined, CidrEntrySplit, CidrEntriesMerged,
+    CidrEntryResized};
+use crate::attributes::Attributes;
+use crate::error::IpamError;
+use crate::schema::AttributeSchema;
 
 /* ---- Creating new Ipam ------------------------ */
 #[derive(Serialize, Deserialize, Clone, Default)]
@@ -94,7 +63,9 @@ pub struct AddCidrEntry {
     pub uuid: Uuid,
     pub id: Option<String>,
     pub sysref: Option<String>,
-    pub attributes: HashSet<Label>    
+    pub attributes: Attributes,
+    /// planned, reserved or active (the default)
+    pub state: Option<EntryState>,
 }
 
 impl Command<Ipam, IpamEvent> for AddCidrEntry {
@@ -102,76 +73,711 @@ impl Command<Ipam, IpamEvent> for AddCidrEntry {
         
         println!(":: Add Cidr Entry");
 
-        let cidr = IpNetwork::from_str(self.cidr.as_str());
-        if cidr.is_err() {
-            return Err(AggregateError::TechnicalError(String::from("format was wrong")))
-        } 
+        let cidr = IpNetwork::from_str(self.cidr.as_str()).map_err(IpamError::from)?;
+        let network = IpNetwork::new(cidr.network(), cidr.prefix()).map_err(IpamError::from)?;
+
+        // host bits, 10.2.2.1/21, are dealt with as the Ipam is configured
+        let policy = ipam.cfg.as_ref().map(|c| c.canonical).unwrap_or_default();
+        let cidr = match policy {
+            CanonicalPolicy::Reject if cidr != network => {
+                return Err(IpamError::NonCanonical(cidr.to_string(), network.to_string()).into())
+            },
+            CanonicalPolicy::Normalize => network,
+            _ => cidr,
+        };
 
-        if ipam.contains(cidr.unwrap()) {
-            return Err(AggregateError::TechnicalError(String::from("cidr already exists")))
+        if ipam.contains(cidr) {
+            return Err(IpamError::DuplicateEntry(cidr.to_string()).into())
         }
 
         let mut cidr_entry = CidrEntry::try_from_with_extras(
-            self.cidr.as_str(),
+            cidr.to_string().as_str(),
             self.id,
             self.sysref,
             self.attributes)?;
+        cidr_entry.state = starting_state(self.state)?;
+        ipam.check_schema(cidr_entry.cidr, &cidr_entry.attributes)?;
 
-        // find the parent of this entry, we just want the id
-        cidr_entry.parent = ipam.parent_of(cidr_entry.cidr).map(|r| r.id);
+        // 10.99.99.68/24 brings 10.99.99.0/24 along with it, when so configured
+        let add_supernet = ipam.cfg.as_ref().map_or(false, |c| c.add_missing_supernet);
+        if add_supernet && !cidr_entry.is_canonical() && !ipam.contains(network) {
+            return Ok(entries_added(ipam, vec![CidrEntry::from(network), cidr_entry])?)
+        }
 
-        // Create the event
-        let event_payload = CidrEntryAdded { cidr_entry };
-        Ok(vec![IpamEvent::CidrEntryAdded(event_payload)])
+        Ok(entry_added(ipam, cidr_entry)?)
     }
 }
 
+/// Add a network entry for every host entry (10.99.99.68/24) whose
+/// network (10.99.99.0/24) is not yet in the Ipam
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AddMissingSupernets {}
 
-// // #[derive(Serialize, Deserialize)]
-// // pub struct ReleaseCidrEntry {
-// //     pub cidr: String
-// // }
+impl Command<Ipam, IpamEvent> for AddMissingSupernets {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
 
-// impl Command<Ipam, IpamEvent> for AddCidrEntry {
-//     fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
-//         let initial = CidrEntry::try_from(self.cidr.as_str())
-//         let entry = CidrEntry { 
-//             ,id: self.id.or(Some(initial.id)).unwrap()
-//             ,sysref: self.sysref
-//             ,attributes: self.attributes
-//             ..initial.clone()
-//         }
-//         ipam.
-//         let event_payload = CidrEntryAdded { entry };
-//         Ok(vec![IpamEvent::IpamEntryAdded(event_payload)])
-//     }
-// }
+        println!(":: Add Missing Supernets");
 
-// impl Command<Ipam, IpamEvent> for ReleaseIpamEntry {
-//     fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
-//         let balance = account.balance - self.amount;
-//         if balance < 0_f64 {
-//             return Err(AggregateError::new("funds not available"));
-//         }
-//         let event_payload = IpamEntryReleased {
-//             amount: self.amount,
-//             balance,
-//         };
-//         Ok(vec![IpamEvent::IpamEntryReleased(event_payload)])
-//     }
-// }
+        let supernets = ipam.missing_supernets().into_iter().map(CidrEntry::from).collect();
+        Ok(entries_added(ipam, supernets)?)
+    }
+}
 
-// impl Command<Ipam, IpamEvent> for AddAttributeToCidr {
-//     fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
-//         let balance = account.balance - self.amount;
-//         if balance < 0_f64 {
-//             return Err(AggregateError::new("funds not available"));
-//         }
-//         let event_payload = AttributesAddedToCidr {
-//             check_number: self.check_number,
-//             amount: self.amount,
-//             balance,
-//         };
-//         Ok(vec![IpamEvent::AttributesAddedToCidr(event_payload)])
-//     }
-// }
+/// The events for placing several new entries, each one placed against
+/// the Ipam as the entries before it leave it
+fn entries_added(ipam: &Ipam, entries: Vec<CidrEntry>) -> Result<Vec<IpamEvent>, IpamError> {
+    let mut scratch = ipam.clone();
+    let mut events = vec![];
+    for cidr_entry in entries {
+        let added = entry_added(&scratch, cidr_entry)?;
+        for e in added.iter() {
+            e.clone().apply(&mut scratch);
+        }
+        events.extend(added);
+    }
+    Ok(events)
+}
+
+/// The events for placing a new entry into the Ipam tree, so long as
+/// the Ipam's conflict policy allows it
+fn entry_added(ipam: &Ipam, mut cidr_entry: CidrEntry) -> Result<Vec<IpamEvent>, IpamError> {
+    // refused here, as apply would only refuse it once the event is stored
+    ipam.check_protocol(cidr_entry.cidr)?;
+    if ipam.contains(cidr_entry.cidr) {
+        return Err(IpamError::DuplicateEntry(cidr_entry.cidr.to_string()))
+    }
+    if ipam.find(&cidr_entry.id).is_some() {
+        return Err(IpamError::DuplicateEntry(cidr_entry.id.to_string()))
+    }
+    ipam.check_conflicts(cidr_entry.cidr)?;
+
+    // find the parent of this entry, we just want the id
+    cidr_entry.parent = ipam.parent_of(cidr_entry.cidr).map(|r| r.id);
+
+    // entries that will now sit directly beneath this one move across to it
+    let reparented = ipam.children_of(cidr_entry.cidr)
+        .into_iter()
+        .map(|child| IpamEvent::CidrEntryReparented(CidrEntryReparented {
+            id: child.id,
+            cidr: child.cidr,
+            parent: Some(cidr_entry.id.clone()),
+        }));
+
+    // Create the event
+    let event_payload = CidrEntryAdded { cidr_entry: cidr_entry.clone() };
+    let mut events = vec![IpamEvent::CidrEntryAdded(event_payload)];
+    events.extend(reparented);
+    Ok(events)
+}
+
+/// The state a new entry starts out in, active unless asked otherwise
+fn starting_state(state: Option<EntryState>) -> Result<EntryState, IpamError> {
+    match state.unwrap_or_default() {
+        s @ EntryState::Planned | s @ EntryState::Reserved | s @ EntryState::Active => Ok(s),
+        s => Err(IpamError::BadRequest(format!("a new entry can not start out {:?}", s).to_lowercase())),
+    }
+}
+
+/// Names the entry a command is aimed at, by its CIDR, id, uuid or sysref.
+/// When more than one is given the first, in that order, is used.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct EntryRef {
+    pub cidr: Option<String>,
+    pub id: Option<String>,
+    pub uuid: Option<Uuid>,
+    pub sysref: Option<String>,
+}
+
+impl EntryRef {
+    pub fn by_id(id: &str) -> Self {
+        EntryRef { id: Some(String::from(id)), ..Default::default() }
+    }
+
+    pub(crate) fn locate(&self, ipam: &Ipam) -> Result<CidrEntry, IpamError> {
+        let (found, named) = match self {
+            EntryRef { cidr: Some(c), .. } => (ipam.find(&IpNetwork::from_str(c.as_str())?), c.clone()),
+            EntryRef { id: Some(i), .. } => (ipam.find(&Box::new(i.clone())), i.clone()),
+            EntryRef { uuid: Some(u), .. } => (ipam.find(u), u.to_string()),
+            EntryRef { sysref: Some(r), .. } => (ipam.find(SysRef(r.as_str())), r.clone()),
+            _ => return Err(IpamError::BadRequest(String::from("one of cidr, id, uuid or sysref is required"))),
+        };
+        found.ok_or(IpamError::EntryNotFound(named))
+    }
+}
+
+/// Resolve the entry a command is aimed at, by its CIDR or by its id
+fn locate(ipam: &Ipam, cidr: &Option<String>, id: &Option<String>) -> Result<CidrEntry, IpamError> {
+    EntryRef { cidr: cidr.clone(), id: id.clone(), ..Default::default() }.locate(ipam)
+}
+
+/* ---- Allocating the next free Cidr Entry ------------------------ */
+/// Allocate the next free block of `prefix_len` inside a parent entry,
+/// named by either `parent_cidr` or `parent_id`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AllocateNextCidr {
+    pub parent_cidr: Option<String>,
+    pub parent_id: Option<String>,
+    pub prefix_len: u8,
+    pub strategy: Option<AllocationStrategy>,
+    pub id: Option<String>,
+    pub sysref: Option<String>,
+    pub attributes: Attributes,
+    pub state: Option<EntryState>,
+}
+
+impl Command<Ipam, IpamEvent> for AllocateNextCidr {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Allocate Next Cidr Entry");
+
+        let parent = allocation_parent(ipam, &self.parent_cidr, &self.parent_id)?;
+
+        let strategy = self.strategy.unwrap_or_default();
+        let cidr = ipam.next_free(parent.cidr, self.prefix_len, strategy)?;
+
+        let mut cidr_entry = CidrEntry::try_from_with_extras(
+            cidr.to_string().as_str(),
+            self.id,
+            self.sysref,
+            self.attributes)?;
+        cidr_entry.state = starting_state(self.state)?;
+        ipam.check_schema(cidr_entry.cidr, &cidr_entry.attributes)?;
+
+        Ok(entry_added(ipam, cidr_entry)?)
+    }
+}
+
+/// Allocate the next free host address (a /32 or /128 entry) inside a
+/// parent entry, named by either `parent_cidr` or `parent_id`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AllocateNextAddress {
+    pub parent_cidr: Option<String>,
+    pub parent_id: Option<String>,
+    pub id: Option<String>,
+    pub sysref: Option<String>,
+    pub attributes: Attributes,
+    pub state: Option<EntryState>,
+}
+
+impl Command<Ipam, IpamEvent> for AllocateNextAddress {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Allocate Next Address");
+
+        let parent = allocation_parent(ipam, &self.parent_cidr, &self.parent_id)?;
+
+        let addr = ipam.next_free_address(parent.cidr)?;
+
+        let mut cidr_entry = CidrEntry::try_from_with_extras(
+            IpNetwork::from(addr).to_string().as_str(),
+            self.id,
+            self.sysref,
+            self.attributes)?;
+        cidr_entry.state = starting_state(self.state)?;
+        ipam.check_schema(cidr_entry.cidr, &cidr_entry.attributes)?;
+
+        Ok(entry_added(ipam, cidr_entry)?)
+    }
+}
+
+
+/// The entry new space is allocated from; a network entry that is not deprecated
+fn allocation_parent(ipam: &Ipam, cidr: &Option<String>, id: &Option<String>) -> Result<CidrEntry, IpamError> {
+    let parent = locate(ipam, cidr, id)?;
+    if !parent.is_canonical() {
+        return Err(IpamError::BadRequest(format!("{} is a host entry, it can not hold other entries", parent.cidr)))
+    }
+    if parent.state == EntryState::Deprecated {
+        return Err(IpamError::BadRequest(format!("{} is deprecated, nothing new is allocated beneath it", parent.cidr)))
+    }
+    Ok(parent)
+}
+
+/* ---- Releasing Cidr Entries ------------------------ */
+/// Release an entry, named by either `cidr` or `id`. The `children` policy
+/// decides what happens to anything beneath it, refusing by default.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ReleaseCidrEntry {
+    pub cidr: Option<String>,
+    pub id: Option<String>,
+    pub children: Option<ChildPolicy>,
+}
+
+impl Command<Ipam, IpamEvent> for ReleaseCidrEntry {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Release Cidr Entry");
+
+        let entry = locate(ipam, &self.cidr, &self.id)?;
+        let children = ipam.children_of(entry.cidr);
+        let now = now_secs();
+
+        let mut events = vec![];
+        match self.children.unwrap_or_default() {
+            ChildPolicy::Refuse if !children.is_empty() => {
+                return Err(IpamError::HasChildren(entry.cidr.to_string()).into())
+            },
+            ChildPolicy::Refuse => (),
+            ChildPolicy::Cascade => {
+                for child in children.iter() {
+                    released_with_descendants(ipam, &child.id, now, &mut events)?;
+                }
+            },
+            ChildPolicy::Reparent => {
+                events.extend(children.into_iter().map(|child| IpamEvent::CidrEntryReparented(CidrEntryReparented {
+                    id: child.id,
+                    cidr: child.cidr,
+                    parent: entry.parent.clone(),
+                })));
+            },
+        }
+
+        released(ipam, entry, now, &mut events);
+        Ok(events)
+    }
+}
+
+/// Release events for an entry and everything beneath it, deepest first
+fn released_with_descendants(ipam: &Ipam, id: &CidrId, now: u64, events: &mut Vec<IpamEvent>) -> Result<(), IpamError> {
+    let entry = ipam.find(id).ok_or_else(|| IpamError::EntryNotFound(id.to_string()))?;
+    for child in ipam.children_of(entry.cidr).iter() {
+        released_with_descendants(ipam, &child.id, now, events)?;
+    }
+    released(ipam, entry, now, events);
+    Ok(())
+}
+
+/// The release of one entry, with its space put in quarantine when the Ipam keeps one
+fn released(ipam: &Ipam, entry: CidrEntry, now: u64, events: &mut Vec<IpamEvent>) {
+    let secs = ipam.cfg.as_ref().map_or(0, |c| c.quarantine_secs);
+    let quarantined = CidrBlockQuarantined { id: entry.id.clone(), cidr: entry.cidr, released_at: now, until: now + secs };
+    unpaired(ipam, &entry, events);
+    events.push(IpamEvent::CidrEntryReleased(CidrEntryReleased { cidr_entry: entry }));
+    if secs > 0 {
+        events.push(IpamEvent::CidrBlockQuarantined(quarantined));
+    }
+}
+
+/// The unpairing of an entry about to be taken out, when it has a pair
+fn unpaired(ipam: &Ipam, entry: &CidrEntry, events: &mut Vec<IpamEvent>) {
+    if let Some(other) = ipam.pair_of(entry) {
+        let (v4, v6) = if entry.cidr.is_ipv4() { (entry.id.clone(), other.id) } else { (other.id, entry.id.clone()) };
+        events.push(IpamEvent::CidrEntriesUnpaired(CidrEntriesUnpaired { v4, v6 }));
+    }
+}
+
+/* ---- Lifecycle of Cidr Entries ------------------------ */
+/// Move an entry on through its lifecycle, see `EntryState::can_become` for
+/// the moves allowed. Moving to released releases the entry, refusing while
+/// it has children.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ChangeCidrEntryState {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+    pub state: EntryState,
+}
+
+impl Command<Ipam, IpamEvent> for ChangeCidrEntryState {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Change Cidr Entry State");
+
+        let entry = self.entry.locate(ipam)?;
+        if entry.state == self.state {
+            return Ok(vec![])
+        }
+        if !entry.state.can_become(self.state) {
+            return Err(IpamError::InvalidTransition(entry.cidr.to_string(), entry.state, self.state).into())
+        }
+        if self.state == EntryState::Released {
+            let release = ReleaseCidrEntry { id: Some(entry.id.to_string()), ..Default::default() };
+            return release.handle(ipam)
+        }
+
+        Ok(vec![IpamEvent::CidrEntryStateChanged(CidrEntryStateChanged {
+            id: entry.id,
+            cidr: entry.cidr,
+            from: entry.state,
+            to: self.state,
+        })])
+    }
+}
+
+/* ---- Splitting and Merging Cidr Entries ------------------------ */
+/// The most prefix lengths a split may go down by, 1024 parts
+const MAX_SPLIT_BITS: u8 = 10;
+
+/// Split an entry into the blocks of `prefix_len` making it up, a /22 into
+/// four /24s. Each child moves beneath the block holding it, so a child
+/// larger than the blocks refuses the split. A child that is itself one of
+/// the blocks stands in for it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SplitCidrEntry {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+    pub prefix_len: u8,
+    pub attributes: Option<AttributeCarry>,
+}
+
+impl Command<Ipam, IpamEvent> for SplitCidrEntry {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Split Cidr Entry");
+
+        let entry = self.entry.locate(ipam)?;
+        if !entry.is_canonical() {
+            return Err(IpamError::BadRequest(format!("{} is a host entry, it can not be split", entry.cidr)).into())
+        }
+        let max = if entry.cidr.is_ipv4() { 32 } else { 128 };
+        if self.prefix_len <= entry.cidr.prefix() || self.prefix_len > max {
+            return Err(IpamError::BadRequest(format!("{} does not split into /{}s", entry.cidr, self.prefix_len)).into())
+        }
+        if self.prefix_len - entry.cidr.prefix() > MAX_SPLIT_BITS {
+            return Err(IpamError::BadRequest(format!("{} splits into too many /{}s", entry.cidr, self.prefix_len)).into())
+        }
+
+        let children = ipam.children_of(entry.cidr);
+        if let Some(child) = children.iter().find(|c| c.cidr.prefix() < self.prefix_len) {
+            return Err(IpamError::BadRequest(format!("{} does not fit inside a /{}", child.cidr, self.prefix_len)).into())
+        }
+
+        let attributes = carried(&[&entry], self.attributes.unwrap_or_default(), &entry.cidr)?;
+        let mut parts = vec![];
+        for cidr in subnets_of(&entry.cidr, self.prefix_len).into_iter().filter(|c| !ipam.contains(*c)) {
+            let mut part = CidrEntry::from(cidr);
+            part.attributes = attributes.clone();
+            part.state = entry.state;
+            ipam.check_schema(part.cidr, &part.attributes)?;
+            parts.push(part);
+        }
+
+        let mut events = vec![];
+        unpaired(ipam, &entry, &mut events);
+        events.push(IpamEvent::CidrEntrySplit(CidrEntrySplit { cidr_entry: entry, parts }));
+        Ok(with_children_moved(ipam, events, children))
+    }
+}
+
+/// Merge sibling entries, named by id, into the one entry covering them; four
+/// /24s back into a /22. They must run on from one another, line up on the
+/// new prefix and share a parent and lifecycle state.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MergeCidrEntries {
+    pub ids: Vec<String>,
+    /// the id and sysref of the merged entry
+    pub id: Option<String>,
+    pub sysref: Option<String>,
+    pub attributes: Option<AttributeCarry>,
+}
+
+impl Command<Ipam, IpamEvent> for MergeCidrEntries {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Merge Cidr Entries");
+
+        let mut entries: Vec<CidrEntry> = vec![];
+        for id in self.ids.iter() {
+            let entry = ipam.find(&Box::new(id.clone())).ok_or_else(|| IpamError::EntryNotFound(id.clone()))?;
+            if entries.iter().any(|e| e.id == entry.id) {
+                return Err(IpamError::BadRequest(format!("{} is named more than once", id)).into())
+            }
+            entries.push(entry);
+        }
+        let first = match entries.first() {
+            Some(first) if entries.len() > 1 => first,
+            _ => return Err(IpamError::BadRequest(String::from("at least two entries are needed to merge")).into()),
+        };
+
+        for e in entries.iter() {
+            if !e.is_canonical() {
+                return Err(IpamError::BadRequest(format!("{} is a host entry, it can not be merged", e.cidr)).into())
+            }
+            if e.cidr.is_ipv4() != first.cidr.is_ipv4() {
+                return Err(IpamError::InvalidProtocol.into())
+            }
+            if e.parent != first.parent {
+                return Err(IpamError::BadRequest(format!("{} and {} are not siblings", first.cidr, e.cidr)).into())
+            }
+            if e.state != first.state {
+                return Err(IpamError::BadRequest(format!("{} and {} are in different states", first.cidr, e.cidr)).into())
+            }
+        }
+
+        let cidrs: Vec<IpNetwork> = entries.iter().map(|e| e.cidr).collect();
+        let cidr = supernet_of(&cidrs)?;
+        if ipam.contains(cidr) {
+            return Err(IpamError::DuplicateEntry(cidr.to_string()).into())
+        }
+
+        let sources: Vec<&CidrEntry> = entries.iter().collect();
+        let attributes = carried(&sources, self.attributes.unwrap_or_default(), &cidr)?;
+        let mut into = CidrEntry::try_from_with_extras(cidr.to_string().as_str(), self.id, self.sysref, attributes)?;
+        // the merged entries go first, so only another entry's id is taken
+        if ipam.find(&into.id).is_some() && !entries.iter().any(|e| e.id == into.id) {
+            return Err(IpamError::DuplicateEntry(into.id.to_string()).into())
+        }
+        into.state = first.state;
+        ipam.check_schema(into.cidr, &into.attributes)?;
+
+        let children = entries.iter().flat_map(|e| ipam.children_of(e.cidr)).collect();
+        let mut events = vec![];
+        for e in entries.iter() {
+            unpaired(ipam, e, &mut events);
+        }
+        events.push(IpamEvent::CidrEntriesMerged(CidrEntriesMerged { cidr_entries: entries, into }));
+        Ok(with_children_moved(ipam, events, children))
+    }
+}
+
+/// The labels an entry made from `sources` starts out with
+fn carried(sources: &[&CidrEntry], carry: AttributeCarry, cidr: &IpNetwork) -> Result<Attributes, IpamError> {
+    let mut attributes = Attributes::default();
+    if carry == AttributeCarry::Drop {
+        return Ok(attributes)
+    }
+    for (key, value) in sources.iter().flat_map(|s| s.attributes.iter()) {
+        let agreed = sources.iter().all(|s| s.attributes.get(key) == Some(value));
+        match attributes.get(key) {
+            _ if carry == AttributeCarry::Common && !agreed => continue,
+            Some(v) if v != value => return Err(IpamError::AttributeExists(cidr.to_string(), key.clone())),
+            _ => { attributes.insert(Label { key: key.clone(), value: value.clone() }); },
+        }
+    }
+    Ok(attributes)
+}
+
+/// The events, followed by a move for each of `children` the events leave
+/// beneath a different closest enclosing network
+fn with_children_moved(ipam: &Ipam, mut events: Vec<IpamEvent>, children: Vec<CidrEntryResult>) -> Vec<IpamEvent> {
+    let mut scratch = ipam.clone();
+    for event in events.iter() {
+        event.clone().apply(&mut scratch);
+    }
+
+    for child in children {
+        let parent = scratch.parent_of(child.cidr).map(|r| r.id);
+        if ipam.find(&child.id).map(|c| c.parent) != Some(parent.clone()) {
+            events.push(IpamEvent::CidrEntryReparented(CidrEntryReparented { id: child.id, cidr: child.cidr, parent }));
+        }
+    }
+    events
+}
+
+/* ---- Resizing Cidr Entries ------------------------ */
+/// Grow or shrink an entry in place, keeping its id, uuid and labels. The
+/// network address stays where it is, so growing needs it on the new boundary;
+/// 10.1.0.0/24 grows to 10.1.0.0/23, 10.1.1.0/24 does not.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ResizeCidrEntry {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+    pub prefix_len: u8,
+}
+
+impl Command<Ipam, IpamEvent> for ResizeCidrEntry {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Resize Cidr Entry");
+
+        let entry = self.entry.locate(ipam)?;
+        if !entry.is_canonical() {
+            return Err(IpamError::BadRequest(format!("{} is a host entry, it can not be resized", entry.cidr)).into())
+        }
+        let to = IpNetwork::new(entry.cidr.ip(), self.prefix_len).map_err(IpamError::from)?;
+        if to.ip() != to.network() {
+            return Err(IpamError::BadRequest(format!("{} is not on a /{} boundary", entry.cidr.ip(), self.prefix_len)).into())
+        }
+        if to == entry.cidr {
+            return Ok(vec![])
+        }
+
+        ipam.check_resize(&entry, to)?;
+        ipam.check_schema(to, &entry.attributes)?;
+
+        let children = ipam.children_of(entry.cidr);
+        let resized = IpamEvent::CidrEntryResized(CidrEntryResized { id: entry.id, from: entry.cidr, to });
+        Ok(with_children_moved(ipam, vec![resized], children))
+    }
+}
+
+/* ---- Changing the Attributes of Cidr Entries ------------------------ */
+/// Add a label to an existing entry, under a key it does not have yet
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AddAttributeToCidr {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+    pub attribute: Label,
+}
+
+impl Command<Ipam, IpamEvent> for AddAttributeToCidr {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+        let entry = self.entry.locate(ipam)?;
+        match entry.attributes.get(&self.attribute.key) {
+            Some(v) if *v == self.attribute.value => Ok(vec![]),
+            Some(_) => Err(IpamError::AttributeExists(entry.cidr.to_string(), self.attribute.key).into()),
+            None => {
+                let mut attributes = entry.attributes.clone();
+                attributes.insert(self.attribute.clone());
+                ipam.check_schema(entry.cidr, &attributes)?;
+                Ok(vec![attribute_added(&entry, self.attribute)])
+            },
+        }
+    }
+}
+
+/// Remove one label from an existing entry
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RemoveAttributeFromCidr {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+    pub attribute: Label,
+}
+
+impl Command<Ipam, IpamEvent> for RemoveAttributeFromCidr {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+        let entry = self.entry.locate(ipam)?;
+        let mut attributes = entry.attributes.clone();
+        if !attributes.remove(&self.attribute) {
+            return Err(IpamError::BadRequest(format!("{} has no attribute {}={}", entry.cidr, self.attribute.key, self.attribute.value)).into())
+        }
+        ipam.check_schema(entry.cidr, &attributes)?;
+        Ok(vec![attribute_removed(&entry, self.attribute)])
+    }
+}
+
+/// Remove the label with the given key from an existing entry, whatever its value
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RemoveAttributeByKeyFromCidr {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+    pub key: String,
+}
+
+impl Command<Ipam, IpamEvent> for RemoveAttributeByKeyFromCidr {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+        let entry = self.entry.locate(ipam)?;
+        match entry.attributes.get(&self.key) {
+            Some(value) => {
+                let mut attributes = entry.attributes.clone();
+                attributes.remove_key(&self.key);
+                ipam.check_schema(entry.cidr, &attributes)?;
+                let label = Label { key: self.key, value: value.clone() };
+                Ok(vec![attribute_removed(&entry, label)])
+            },
+            None => Err(IpamError::BadRequest(format!("{} has no attribute {}", entry.cidr, self.key)).into()),
+        }
+    }
+}
+
+/// Set the label for a key, replacing whatever value the entry held for that key
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ReplaceAttributeOnCidr {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+    pub attribute: Label,
+}
+
+impl Command<Ipam, IpamEvent> for ReplaceAttributeOnCidr {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+        let entry = self.entry.locate(ipam)?;
+        let mut events = vec![];
+        match entry.attributes.get(&self.attribute.key) {
+            Some(v) if *v == self.attribute.value => return Ok(events),
+            Some(v) => {
+                let old = Label { key: self.attribute.key.clone(), value: v.clone() };
+                events.push(attribute_removed(&entry, old));
+            },
+            None => (),
+        }
+        let mut attributes = entry.attributes.clone();
+        attributes.insert(self.attribute.clone());
+        ipam.check_schema(entry.cidr, &attributes)?;
+        events.push(attribute_added(&entry, self.attribute));
+        Ok(events)
+    }
+}
+
+fn attribute_added(entry: &CidrEntry, attribute: Label) -> IpamEvent {
+    IpamEvent::CidrAttributeAdded(CidrAttributeAdded { id: entry.id.clone(), cidr: entry.cidr, attribute })
+}
+
+fn attribute_removed(entry: &CidrEntry, attribute: Label) -> IpamEvent {
+    IpamEvent::CidrAttributeRemoved(CidrAttributeRemoved { id: entry.id.clone(), cidr: entry.cidr, attribute })
+}
+
+/* ---- The Attribute Schema ------------------------ */
+/// Set the schema entries' attributes are held to, or clear it with None.
+/// Entries already breaking the new schema stay as they are, see `Ipam::schema_violations`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SetAttributeSchema {
+    pub schema: Option<AttributeSchema>,
+}
+
+impl Command<Ipam, IpamEvent> for SetAttributeSchema {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Set Attribute Schema");
+
+        if let Some(schema) = &self.schema {
+            schema.validate()?;
+        }
+        if self.schema == ipam.schema {
+            return Ok(vec![])
+        }
+        Ok(vec![IpamEvent::AttributeSchemaChanged(AttributeSchemaChanged { schema: self.schema })])
+    }
+}
+
+/* ---- Pairing V4 and V6 Cidr Entries ------------------------ */
+/// Link a V4 entry with its V6 counterpart, the two halves of one
+/// dual-stack subnet (or VLAN)
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PairCidrEntries {
+    pub v4: EntryRef,
+    pub v6: EntryRef,
+}
+
+impl Command<Ipam, IpamEvent> for PairCidrEntries {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Pair Cidr Entries");
+
+        let v4 = self.v4.locate(ipam)?;
+        let v6 = self.v6.locate(ipam)?;
+        if !v4.cidr.is_ipv4() || !v6.cidr.is_ipv6() {
+            return Err(IpamError::BadRequest(format!("{} and {} are not a V4 and a V6 entry", v4.cidr, v6.cidr)).into())
+        }
+        if v4.pair.as_ref() == Some(&v6.id) {
+            return Ok(vec![])
+        }
+        for entry in [&v4, &v6].iter() {
+            if let Some(p) = &entry.pair {
+                return Err(IpamError::AlreadyPaired(entry.cidr.to_string(), p.to_string()).into())
+            }
+        }
+
+        Ok(vec![IpamEvent::CidrEntriesPaired(CidrEntriesPaired { v4: v4.id, v6: v6.id })])
+    }
+}
+
+/// Drop the link between an entry and its counterpart of the other family
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UnpairCidrEntry {
+    #[serde(flatten)]
+    pub entry: EntryRef,
+}
+
+impl Command<Ipam, IpamEvent> for UnpairCidrEntry {
+    fn handle(self, ipam: &Ipam) -> Result<Vec<IpamEvent>, AggregateError> {
+
+        println!(":: Unpair Cidr Entry");
+
+        let entry = self.entry.locate(ipam)?;
+        let other = match ipam.pair_of(&entry) {
+            Some(other) => other,
+            None => return Ok(vec![]),
+        };
+        let (v4, v6) = if entry.cidr.is_ipv4() { (entry.id, other.id) } else { (other.id, entry.id) };
+        Ok(vec![IpamEvent::CidrEntriesUnpaired(CidrEntriesUnpaired { v4, v6 })])
+    }
+}
@@ -0,0 +1,189 @@
+//! What changed in an Ipam between two points in its history.
+//!
+//! Entries are matched by uuid, so an entry that has been resized or moved
+//! shows as a change rather than as one released and another added.
+//!
+//! ```text
+//! --- ipam_1 @ 2023-05-01T00:00:00Z
+//! +++ ipam_1 @ 42
+//! +10.1.4.0/24 vpc-a
+//! -10.1.9.0/24 vpc-b
+//!  10.1.2.0/24 vpc-c
+//! -    env=dev
+//! +    env=prod
+//! ```
+
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use uuid::Uuid;
+
+use crate::attributes::AttributeValue;
+use crate::ipam_model::{CidrEntry, CidrId, EntryState, Ipam};
+
+/// A label whose value differs, None where the entry did not have the key
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LabelChange {
+    pub key: String,
+    pub from: Option<AttributeValue>,
+    pub to: Option<AttributeValue>,
+}
+
+/// Something that moved from one value to another
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Moved<T> {
+    pub from: T,
+    pub to: T,
+}
+
+/// The ways an entry present at both points differs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EntryChange {
+    pub uuid: Uuid,
+    pub id: CidrId,
+    pub cidr: IpNetwork,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resized: Option<Moved<IpNetwork>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reparented: Option<Moved<Option<CidrId>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<Moved<EntryState>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<LabelChange>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct IpamDiff {
+    pub added: Vec<CidrEntry>,
+    pub released: Vec<CidrEntry>,
+    pub changed: Vec<EntryChange>,
+}
+
+fn moved<T: PartialEq + Clone>(from: &T, to: &T) -> Option<Moved<T>> {
+    if from == to { None } else { Some(Moved { from: from.clone(), to: to.clone() }) }
+}
+
+/// How `to` differs from `from`, each list in address order
+pub fn diff(from: &Ipam, to: &Ipam) -> IpamDiff {
+    let before: HashMap<Uuid, &CidrEntry> = from.cidrs.iter().map(|ce| (ce.uuid, ce)).collect();
+    let after: HashMap<Uuid, &CidrEntry> = to.cidrs.iter().map(|ce| (ce.uuid, ce)).collect();
+
+    let mut added: Vec<CidrEntry> = to.cidrs.iter().filter(|ce| !before.contains_key(&ce.uuid)).cloned().collect();
+    let mut released: Vec<CidrEntry> = from.cidrs.iter().filter(|ce| !after.contains_key(&ce.uuid)).cloned().collect();
+    added.sort_by_key(|ce| ce.address_order());
+    released.sort_by_key(|ce| ce.address_order());
+
+    let mut changed: Vec<(&CidrEntry, EntryChange)> = to.cidrs.iter()
+        .filter_map(|now| before.get(&now.uuid).map(|then| (now, entry_change(then, now))))
+        .filter(|(_, c)| c.resized.is_some() || c.reparented.is_some() || c.state.is_some() || !c.labels.is_empty())
+        .collect();
+    changed.sort_by_key(|(now, _)| now.address_order());
+
+    IpamDiff { added, released, changed: changed.into_iter().map(|(_, c)| c).collect() }
+}
+
+fn entry_change(then: &CidrEntry, now: &CidrEntry) -> EntryChange {
+    let keys: BTreeSet<&String> = then.attributes.iter().chain(now.attributes.iter()).map(|(k, _)| k).collect();
+    let labels = keys.into_iter()
+        .filter(|k| then.attributes.get(k) != now.attributes.get(k))
+        .map(|k| LabelChange { key: k.clone(), from: then.attributes.get(k).cloned(), to: now.attributes.get(k).cloned() })
+        .collect();
+
+    EntryChange {
+        uuid: now.uuid,
+        id: now.id.clone(),
+        cidr: now.cidr,
+        resized: moved(&then.cidr, &now.cidr),
+        reparented: moved(&then.parent, &now.parent),
+        state: moved(&then.state, &now.state),
+        labels,
+    }
+}
+
+impl IpamDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.released.is_empty() && self.changed.is_empty()
+    }
+
+    /// The diff as text, in the manner of a unified diff. `from` and `to`
+    /// name the two points in the header.
+    pub fn unified(&self, name: &str, from: &str, to: &str) -> String {
+        let mut out = String::new();
+        let none = String::from("none");
+        let _ = writeln!(out, "--- {} @ {}", name, from);
+        let _ = writeln!(out, "+++ {} @ {}", name, to);
+        for ce in self.added.iter() {
+            let _ = writeln!(out, "+{} {}", ce.cidr, ce.id);
+        }
+        for ce in self.released.iter() {
+            let _ = writeln!(out, "-{} {}", ce.cidr, ce.id);
+        }
+        for c in self.changed.iter() {
+            let _ = writeln!(out, " {} {}", c.cidr, c.id);
+            if let Some(m) = &c.resized {
+                let _ = writeln!(out, "-    cidr {}\n+    cidr {}", m.from, m.to);
+            }
+            if let Some(m) = &c.reparented {
+                let _ = writeln!(out, "-    parent {}\n+    parent {}",
+                    m.from.as_deref().unwrap_or(&none), m.to.as_deref().unwrap_or(&none));
+            }
+            if let Some(m) = &c.state {
+                let _ = writeln!(out, "-    state {:?}\n+    state {:?}", m.from, m.to);
+            }
+            for l in c.labels.iter() {
+                if let Some(v) = &l.from {
+                    let _ = writeln!(out, "-    {}={}", l.key, v);
+                }
+                if let Some(v) = &l.to {
+                    let _ = writeln!(out, "+    {}={}", l.key, v);
+                }
+            }
+        }
+        out
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ipam_model::Label;
+
+    fn entry(ipam: &mut Ipam, cidr: &str, id: &str) -> CidrEntry {
+        let mut ce = CidrEntry::from(cidr.parse::<IpNetwork>().unwrap());
+        ce.id = Box::new(String::from(id));
+        ipam.cidrs.push(ce.clone());
+        ce
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut before = Ipam::default();
+        entry(&mut before, "10.1.0.0/16", "top");
+        let kept = entry(&mut before, "10.1.2.0/24", "vpc-c");
+        entry(&mut before, "10.1.9.0/24", "vpc-b");
+        before.cidrs[1].attributes.insert(Label::new("env", "dev"));
+
+        let mut after = before.clone();
+        after.cidrs.remove(2);
+        entry(&mut after, "10.1.4.0/24", "vpc-a");
+        after.cidrs[1].attributes.insert(Label::new("env", "prod"));
+        after.cidrs[1].attributes.insert(Label::new("site", "syd"));
+        after.cidrs[1].cidr = "10.1.2.0/23".parse().unwrap();
+
+        let d = diff(&before, &after);
+        assert_eq!(d.added.iter().map(|ce| ce.id.to_string()).collect::<Vec<_>>(), vec!["vpc-a"]);
+        assert_eq!(d.released.iter().map(|ce| ce.id.to_string()).collect::<Vec<_>>(), vec!["vpc-b"]);
+        assert_eq!(d.changed.len(), 1);
+        assert_eq!(d.changed[0].uuid, kept.uuid);
+        assert_eq!(d.changed[0].labels.iter().map(|l| l.key.as_str()).collect::<Vec<_>>(), vec!["env", "site"]);
+        assert!(d.changed[0].resized.is_some() && d.changed[0].reparented.is_none());
+
+        assert_eq!(d.unified("ipam_1", "1", "2"), "--- ipam_1 @ 1\n+++ ipam_1 @ 2\n+10.1.4.0/24 vpc-a\n-10.1.9.0/24 vpc-b\n \
+            10.1.2.0/23 vpc-c\n-    cidr 10.1.2.0/24\n+    cidr 10.1.2.0/23\n-    env=dev\n+    env=prod\n+    site=syd\n");
+
+        assert!(diff(&after, &after).is_empty());
+    }
+}
@@ -1,6 +1,10 @@
 use thiserror::Error;
+use std::collections::HashMap;
 // use std::convert::From;
-use cqrs_es::AggregateError;
+use cqrs_es::{AggregateError, UserErrorPayload};
+
+use crate::ipam_model::EntryState;
+use crate::schema::Violation;
 
 use actix_web::{error::ResponseError, HttpResponse};
 use actix_web::{HttpRequest};
@@ -49,6 +53,48 @@ pub enum IpamError {
     #[error("CidrEntry is the wrong Protocol. either V6 or V4 ")]    
     InvalidProtocol,
 
+    #[error("CidrEntry {0} already exists")]
+    DuplicateEntry(String),
+
+    #[error("No CidrEntry found for {0}")]
+    EntryNotFound(String),
+
+    #[error("No free space left for {0}")]
+    NoFreeSpace(String),
+
+    #[error("CidrEntry {0} still has child entries")]
+    HasChildren(String),
+
+    #[error("Invalid query, {0}")]
+    InvalidQuery(String),
+
+    #[error("{0} has host bits set, the network is {1}")]
+    NonCanonical(String, String),
+
+    #[error("{0} conflicts with CidrEntry {1}")]
+    Conflict(String, String),
+
+    #[error("{0} is not inside any pool")]
+    NotInPool(String),
+
+    #[error("{0} is already paired with {1}")]
+    AlreadyPaired(String, String),
+
+    #[error("{0} already has a value for attribute {1}")]
+    AttributeExists(String, String),
+
+    #[error("Invalid attribute schema, {0}")]
+    InvalidSchema(String),
+
+    #[error("{0} does not fit the attribute schema: {}", describe(.1))]
+    SchemaViolation(String, Vec<Violation>),
+
+    #[error("{0} can not move from {1:?} to {2:?}")]
+    InvalidTransition(String, EntryState, EntryState),
+
+    #[error("{0} changed after event {1}, try again")]
+    ConcurrentUpdate(String, usize),
+
     #[error("The request was badness::\n{0}")]
     BadRequest(String),
 
@@ -64,9 +110,74 @@ pub enum IpamError {
 }
 
 
+fn describe(violations: &[Violation]) -> String {
+    let each: Vec<String> = violations.iter().map(|v| format!("{} {}", v.key, v.reason)).collect();
+    each.join("; ")
+}
+
+impl IpamError {
+    /// A stable name for the error, handed back to API callers
+    pub fn code(&self) -> &'static str {
+        match self {
+            IpamError::InvalidEntry(_) => "InvalidEntry",
+            IpamError::InvalidProtocol => "InvalidProtocol",
+            IpamError::DuplicateEntry(_) => "DuplicateEntry",
+            IpamError::EntryNotFound(_) => "EntryNotFound",
+            IpamError::NoFreeSpace(_) => "NoFreeSpace",
+            IpamError::HasChildren(_) => "HasChildren",
+            IpamError::InvalidQuery(_) => "InvalidQuery",
+            IpamError::NonCanonical(_, _) => "NonCanonical",
+            IpamError::Conflict(_, _) => "Conflict",
+            IpamError::NotInPool(_) => "NotInPool",
+            IpamError::AlreadyPaired(_, _) => "AlreadyPaired",
+            IpamError::AttributeExists(_, _) => "AttributeExists",
+            IpamError::InvalidSchema(_) => "InvalidSchema",
+            IpamError::SchemaViolation(_, _) => "SchemaViolation",
+            IpamError::InvalidTransition(_, _, _) => "InvalidTransition",
+            IpamError::ConcurrentUpdate(_, _) => "ConcurrentUpdate",
+            IpamError::BadRequest(_) => "BadRequest",
+            IpamError::BadRequestPayload(_) => "BadRequestPayload",
+            IpamError::PayloadTooLarge => "PayloadTooLarge",
+            IpamError::InternalServerError => "InternalServerError",
+        }
+    }
+
+    /// The detail behind an error, keyed for callers to pick through.
+    /// For a schema violation, each broken key with the reason.
+    fn params(&self) -> Option<HashMap<String, String>> {
+        match self {
+            IpamError::SchemaViolation(_, violations) => {
+                Some(violations.iter().map(|v| (v.key.clone(), v.reason.clone())).collect())
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Anything the caller got wrong is a UserError, carrying the IpamError code
 impl std::convert::From<IpamError> for cqrs_es::AggregateError {
     fn from(err: IpamError) -> AggregateError {
-        AggregateError::TechnicalError(err.to_string())
+        match err {
+            IpamError::InternalServerError => AggregateError::TechnicalError(err.to_string()),
+            _ => AggregateError::UserError(UserErrorPayload {
+                code: Some(String::from(err.code())),
+                message: Some(err.to_string()),
+                params: err.params(),
+            }),
+        }
+    }
+}
+
+/// The http response for a command that failed
+pub fn aggregate_error_response(err: &AggregateError) -> HttpResponse {
+    match err {
+        AggregateError::UserError(payload) => match payload.code.as_deref() {
+            Some("EntryNotFound") => HttpResponse::NotFound().json(payload),
+            Some("DuplicateEntry") | Some("NoFreeSpace") | Some("HasChildren") | Some("Conflict") | Some("AlreadyPaired")
+                | Some("AttributeExists") | Some("InvalidTransition") | Some("ConcurrentUpdate") => HttpResponse::Conflict().json(payload),
+            _ => HttpResponse::BadRequest().json(payload),
+        },
+        AggregateError::TechnicalError(msg) => HttpResponse::InternalServerError().json(msg),
     }
 }
 
@@ -80,6 +191,20 @@ impl ResponseError for IpamError {
             IpamError::BadRequest(_) => HttpResponse::BadRequest().json(format!("{}",self)),
             IpamError::BadRequestPayload(_) => HttpResponse::BadRequest().json(format!("{}",self)),
             IpamError::InvalidProtocol => HttpResponse::BadRequest().json(format!("{}",self)),
+            IpamError::DuplicateEntry(_) => HttpResponse::Conflict().json(format!("{}",self)),
+            IpamError::EntryNotFound(_) => HttpResponse::NotFound().json(format!("{}",self)),
+            IpamError::NoFreeSpace(_) => HttpResponse::Conflict().json(format!("{}",self)),
+            IpamError::HasChildren(_) => HttpResponse::Conflict().json(format!("{}",self)),
+            IpamError::InvalidQuery(_) => HttpResponse::BadRequest().json(format!("{}",self)),
+            IpamError::NonCanonical(_, _) => HttpResponse::BadRequest().json(format!("{}",self)),
+            IpamError::Conflict(_, _) => HttpResponse::Conflict().json(format!("{}",self)),
+            IpamError::NotInPool(_) => HttpResponse::BadRequest().json(format!("{}",self)),
+            IpamError::AlreadyPaired(_, _) => HttpResponse::Conflict().json(format!("{}",self)),
+            IpamError::AttributeExists(_, _) => HttpResponse::Conflict().json(format!("{}",self)),
+            IpamError::InvalidSchema(_) => HttpResponse::BadRequest().json(format!("{}",self)),
+            IpamError::SchemaViolation(_, violations) => HttpResponse::BadRequest().json(violations),
+            IpamError::InvalidTransition(_, _, _) => HttpResponse::Conflict().json(format!("{}",self)),
+            IpamError::ConcurrentUpdate(_, _) => HttpResponse::Conflict().json(format!("{}",self)),
             // IpamError::Unauthorized => HttpResponse::Unauthorized().json("Unauthorized"),
             // IpamError::NotFound => HttpResponse::NotFound().json("Not Found"),
             // IpamError::Forbidden(ref message) => HttpResponse::Forbidden().json(message),
@@ -1,13 +1,31 @@
 use cqrs_es::DomainEvent;
+use log::error;
 use serde::{Deserialize, Serialize};
 
-use crate::ipam_model::{CidrEntry, IPProtocolFamily, Ipam, IpamConfig};
+use crate::ipam_model::{CidrEntry, CidrId, EntryState, IPProtocolFamily, Ipam, IpamConfig, Label, Quarantined};
+use crate::schema::AttributeSchema;
+use ipnetwork::IpNetwork;
 use uuid::Uuid;
 
+/// The events an Ipam is built from. The commands raising them check
+/// everything `apply` relies on, so an event that will not apply is a bug,
+/// and is logged as an error rather than stored unnoticed.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum IpamEvent {
     IpamCreated(IpamCreated),
     CidrEntryAdded(CidrEntryAdded),
+    CidrEntryReparented(CidrEntryReparented),
+    CidrEntryReleased(CidrEntryReleased),
+    CidrAttributeAdded(CidrAttributeAdded),
+    CidrAttributeRemoved(CidrAttributeRemoved),
+    CidrEntriesPaired(CidrEntriesPaired),
+    CidrEntriesUnpaired(CidrEntriesUnpaired),
+    AttributeSchemaChanged(AttributeSchemaChanged),
+    CidrEntryStateChanged(CidrEntryStateChanged),
+    CidrBlockQuarantined(CidrBlockQuarantined),
+    CidrEntrySplit(CidrEntrySplit),
+    CidrEntriesMerged(CidrEntriesMerged),
+    CidrEntryResized(CidrEntryResized),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -21,7 +39,9 @@ pub struct IpamCreated {
 impl DomainEvent<Ipam> for IpamCreated {
     fn apply(self, ipam: &mut Ipam) {
         ipam.id = self.id;
-        ipam.protocol = self.protocol
+        ipam.uuid = self.uuid;
+        ipam.protocol = self.protocol;
+        ipam.cfg = self.cfg;
     }
 }
 
@@ -30,6 +50,18 @@ impl DomainEvent<Ipam> for IpamEvent {
         match self {
             IpamEvent::IpamCreated(e) => e.apply(ipam),
             IpamEvent::CidrEntryAdded(e) => e.apply(ipam),
+            IpamEvent::CidrEntryReparented(e) => e.apply(ipam),
+            IpamEvent::CidrEntryReleased(e) => e.apply(ipam),
+            IpamEvent::CidrAttributeAdded(e) => e.apply(ipam),
+            IpamEvent::CidrAttributeRemoved(e) => e.apply(ipam),
+            IpamEvent::CidrEntriesPaired(e) => e.apply(ipam),
+            IpamEvent::CidrEntriesUnpaired(e) => e.apply(ipam),
+            IpamEvent::AttributeSchemaChanged(e) => e.apply(ipam),
+            IpamEvent::CidrEntryStateChanged(e) => e.apply(ipam),
+            IpamEvent::CidrBlockQuarantined(e) => e.apply(ipam),
+            IpamEvent::CidrEntrySplit(e) => e.apply(ipam),
+            IpamEvent::CidrEntriesMerged(e) => e.apply(ipam),
+            IpamEvent::CidrEntryResized(e) => e.apply(ipam),
         }
     }
 }
@@ -41,19 +73,211 @@ pub struct CidrEntryAdded {
 
 impl DomainEvent<Ipam> for CidrEntryAdded {
     fn apply(self, ipam: &mut Ipam) {
-        
-        match ipam.add_entry(self.cidr_entry) {
-            Err(e) => (),// deal with it,
-            Ok(ce) => { 
-                // get the potential children of this entry
-                let children = ipam.children_of(ce.cidr);
-                // for each child, fire new commands to update the parent
-                for c in children.iter() {
-                    // need a new command to set a parent
-                    println!(":: need to set parent of {} to {:?}", c, ce);
-                }
+        if let Err(e) = ipam.add_entry(self.cidr_entry) {
+            error!("CidrEntryAdded not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
+
+/// An existing entry has moved beneath a different parent, typically
+/// because a closer supernet was added above it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntryReparented {
+    pub id: CidrId,
+    pub cidr: IpNetwork,
+    pub parent: Option<CidrId>,
+}
+
+impl DomainEvent<Ipam> for CidrEntryReparented {
+    fn apply(self, ipam: &mut Ipam) {
+        if let Err(e) = ipam.set_parent(&self.id, self.parent) {
+            error!("CidrEntryReparented not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
+
+/// An entry has been released from the Ipam. The event carries the entry
+/// as it was when released, so it stays in the history.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntryReleased {
+    pub cidr_entry: CidrEntry,
+}
+
+impl DomainEvent<Ipam> for CidrEntryReleased {
+    fn apply(self, ipam: &mut Ipam) {
+        if let Err(e) = ipam.remove_entry(&self.cidr_entry.id) {
+            error!("CidrEntryReleased not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
+
+/// An entry has been split into the smaller blocks making it up. A block
+/// already held by an entry of its own is not among the parts. The entry's
+/// children are moved by the CidrEntryReparented events that follow.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntrySplit {
+    pub cidr_entry: CidrEntry,
+    pub parts: Vec<CidrEntry>,
+}
+
+impl DomainEvent<Ipam> for CidrEntrySplit {
+    fn apply(self, ipam: &mut Ipam) {
+        if let Err(e) = ipam.remove_entry(&self.cidr_entry.id) {
+            error!("CidrEntrySplit not applied to {}: {}", ipam.id, e);
+            return;
+        }
+        for part in self.parts {
+            if let Err(e) = ipam.add_entry(part) {
+                error!("CidrEntrySplit part not applied to {}: {}", ipam.id, e);
+            }
+        }
+    }
+}
+
+/// Sibling entries have been merged into the one entry covering them all.
+/// Their children are moved by the CidrEntryReparented events that follow.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntriesMerged {
+    pub cidr_entries: Vec<CidrEntry>,
+    pub into: CidrEntry,
+}
+
+impl DomainEvent<Ipam> for CidrEntriesMerged {
+    fn apply(self, ipam: &mut Ipam) {
+        for entry in self.cidr_entries.iter() {
+            if let Err(e) = ipam.remove_entry(&entry.id) {
+                error!("CidrEntriesMerged not applied to {}: {}", ipam.id, e);
+                return;
             }
         }
+        if let Err(e) = ipam.add_entry(self.into) {
+            error!("CidrEntriesMerged not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
+
+/// An entry has grown or shrunk in place, keeping its id, uuid and labels
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntryResized {
+    pub id: CidrId,
+    pub from: IpNetwork,
+    pub to: IpNetwork,
+}
+
+impl DomainEvent<Ipam> for CidrEntryResized {
+    fn apply(self, ipam: &mut Ipam) {
+        if let Err(e) = ipam.resize_entry(&self.id, self.to) {
+            error!("CidrEntryResized not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
+
+/// The space of a released entry is held back from allocation until
+/// `until`, both in seconds since the epoch
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrBlockQuarantined {
+    pub id: CidrId,
+    pub cidr: IpNetwork,
+    pub released_at: u64,
+    pub until: u64,
+}
+
+impl DomainEvent<Ipam> for CidrBlockQuarantined {
+    fn apply(self, ipam: &mut Ipam) {
+        let block = Quarantined { id: self.id, cidr: self.cidr, until: self.until };
+        ipam.quarantine_block(block, self.released_at);
+    }
+}
+
+/// An entry has moved from one lifecycle state to another
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntryStateChanged {
+    pub id: CidrId,
+    pub cidr: IpNetwork,
+    pub from: EntryState,
+    pub to: EntryState,
+}
+
+impl DomainEvent<Ipam> for CidrEntryStateChanged {
+    fn apply(self, ipam: &mut Ipam) {
+        if let Err(e) = ipam.set_state(&self.id, self.to) {
+            error!("CidrEntryStateChanged not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
 
+/// A label has been added to an existing entry
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrAttributeAdded {
+    pub id: CidrId,
+    pub cidr: IpNetwork,
+    pub attribute: Label,
+}
+
+impl DomainEvent<Ipam> for CidrAttributeAdded {
+    fn apply(self, ipam: &mut Ipam) {
+        match ipam.attributes_mut(&self.id) {
+            Ok(attributes) => { attributes.insert(self.attribute); },
+            Err(e) => error!("CidrAttributeAdded not applied to {}: {}", ipam.id, e),
+        }
+    }
+}
+
+/// A label has been taken off an existing entry
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrAttributeRemoved {
+    pub id: CidrId,
+    pub cidr: IpNetwork,
+    pub attribute: Label,
+}
+
+impl DomainEvent<Ipam> for CidrAttributeRemoved {
+    fn apply(self, ipam: &mut Ipam) {
+        match ipam.attributes_mut(&self.id) {
+            Ok(attributes) => { attributes.remove(&self.attribute); },
+            Err(e) => error!("CidrAttributeRemoved not applied to {}: {}", ipam.id, e),
+        }
+    }
+}
+
+/// A V4 entry and a V6 entry have been linked, as one dual-stack subnet
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntriesPaired {
+    pub v4: CidrId,
+    pub v6: CidrId,
+}
+
+impl DomainEvent<Ipam> for CidrEntriesPaired {
+    fn apply(self, ipam: &mut Ipam) {
+        if let Err(e) = ipam.set_pair(&self.v4, &self.v6) {
+            error!("CidrEntriesPaired not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
+
+/// The link between a V4 entry and a V6 entry has been dropped
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CidrEntriesUnpaired {
+    pub v4: CidrId,
+    pub v6: CidrId,
+}
+
+impl DomainEvent<Ipam> for CidrEntriesUnpaired {
+    fn apply(self, ipam: &mut Ipam) {
+        if let Err(e) = ipam.clear_pair(&self.v4) {
+            error!("CidrEntriesUnpaired not applied to {}: {}", ipam.id, e);
+        }
+    }
+}
+
+/// The Ipam's attribute schema has been set, or cleared when None
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AttributeSchemaChanged {
+    pub schema: Option<AttributeSchema>,
+}
+
+impl DomainEvent<Ipam> for AttributeSchemaChanged {
+    fn apply(self, ipam: &mut Ipam) {
+        ipam.schema = self.schema;
     }
 }
@@ -0,0 +1,454 @@
+//! The embedded backend, everything in one append-only file of JSON lines.
+//!
+//! ```text
+//! {"record":"event","aggregate_type":"Ipam","aggregate_id":"...","sequence":1,"payload":{...},"metadata":{...},"time":"2023-05-01T00:00:00Z"}
+//! {"record":"snapshot","aggregate_type":"Ipam","aggregate_id":"...","last_sequence":500,"payload":{...}}
+//! {"record":"view","name":"ipam_query","id":"...","version":3,"payload":{...}}
+//! ```
+//!
+//! The file is read once when first opened and held in memory from then on;
+//! every write is appended and synced before it is taken in. A later snapshot
+//! or view replaces an earlier one, and those left behind are dropped by
+//! rewriting the file the next time it is opened, or sooner, once there are
+//! more of them than records in use.
+//!
+//! Appends are checked and made under one lock, so only one commit can follow
+//! any given event. That lock is only good within one process, so opening the
+//! file also takes an exclusive OS lock on `<path>.lock`, and a second esipam
+//! process is refused the file for as long as the first has it open.
+
+use chrono::{DateTime, Utc};
+use cqrs_es::AggregateError;
+use fs2::FileExt;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::backend::{concurrent_update, technical, Backend, StoredEvent};
+use crate::queries::PointInTime;
+
+/// The fewest superseded records worth rewriting the file for while it is open
+const COMPACT_AFTER: usize = 1000;
+
+/// The files opened by this process, so every request shares the one in memory copy
+static OPEN: Mutex<Vec<(PathBuf, Arc<Mutex<FileDb>>)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Record {
+    Event {
+        aggregate_type: String,
+        aggregate_id: String,
+        sequence: usize,
+        payload: Value,
+        metadata: HashMap<String, String>,
+        time: String,
+    },
+    Snapshot {
+        aggregate_type: String,
+        aggregate_id: String,
+        last_sequence: usize,
+        payload: Value,
+    },
+    View {
+        name: String,
+        id: String,
+        version: usize,
+        payload: Value,
+    },
+}
+
+type Key = (String, String);
+
+fn key(a: &str, b: &str) -> Key {
+    (String::from(a), String::from(b))
+}
+
+/// The file whose lock says which process has the store at `path`
+pub(crate) fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.to_path_buf().into_os_string();
+    lock.push(".lock");
+    PathBuf::from(lock)
+}
+
+struct FileDb {
+    path: PathBuf,
+    file: File,
+    /// held locked for as long as the store is open, compacting swaps `file` out from under any lock on it
+    _lock: File,
+    events: BTreeMap<Key, Vec<StoredEvent>>,
+    snapshots: HashMap<Key, (usize, Value)>,
+    views: BTreeMap<Key, (usize, Value)>,
+    /// snapshots and views in the file that a later record replaced
+    superseded: usize,
+    /// how many superseded records to let by before compacting while open, `COMPACT_AFTER`
+    compact_after: usize,
+}
+
+impl FileDb {
+    fn load(path: &Path) -> Result<Self, AggregateError> {
+        let lock = OpenOptions::new().create(true).write(true).open(lock_path(path)).map_err(technical)?;
+        if lock.try_lock_exclusive().is_err() {
+            return Err(AggregateError::TechnicalError(
+                format!("{} is open in another esipam process, only one may use it at a time", path.display())));
+        }
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path).map_err(technical)?;
+        let mut db = FileDb {
+            path: path.to_path_buf(),
+            file,
+            _lock: lock,
+            events: BTreeMap::new(),
+            snapshots: HashMap::new(),
+            views: BTreeMap::new(),
+            superseded: 0,
+            compact_after: COMPACT_AFTER,
+        };
+
+        let lines: Vec<String> = BufReader::new(&db.file).lines().collect::<Result<_, _>>().map_err(technical)?;
+        let mut torn = false;
+        for (n, line) in lines.iter().enumerate() {
+            match serde_json::from_str::<Record>(line) {
+                Ok(record) => db.take(record)?,
+                // a write cut short as the process died, it was never acknowledged
+                Err(_) if n + 1 == lines.len() => torn = true,
+                Err(e) => return Err(AggregateError::TechnicalError(format!("{} line {}: {}", path.display(), n + 1, e))),
+            }
+        }
+
+        if torn || db.superseded > 0 {
+            db.compact()?;
+        }
+        Ok(db)
+    }
+
+    /// Takes a record into memory, it is already in the file
+    fn take(&mut self, record: Record) -> Result<(), AggregateError> {
+        match record {
+            Record::Event { aggregate_type, aggregate_id, sequence, payload, metadata, time } => {
+                let events = self.events.entry(key(&aggregate_type, &aggregate_id)).or_default();
+                if sequence != events.len() + 1 {
+                    return Err(AggregateError::TechnicalError(
+                        format!("{} event {} follows {} in {}", aggregate_id, sequence, events.len(), self.path.display())));
+                }
+                let timestamp = DateTime::parse_from_rfc3339(&time).map_err(technical)?.with_timezone(&Utc);
+                events.push(StoredEvent { sequence, payload, metadata, timestamp });
+            },
+            Record::Snapshot { aggregate_type, aggregate_id, last_sequence, payload } => {
+                if self.snapshots.insert(key(&aggregate_type, &aggregate_id), (last_sequence, payload)).is_some() {
+                    self.superseded += 1;
+                }
+            },
+            Record::View { name, id, version, payload } => {
+                if self.views.insert(key(&name, &id), (version, payload)).is_some() {
+                    self.superseded += 1;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Appends the records to the file, then takes them in
+    fn write(&mut self, records: Vec<Record>) -> Result<(), AggregateError> {
+        let mut out = String::new();
+        for record in records.iter() {
+            out.push_str(&serde_json::to_string(record).map_err(technical)?);
+            out.push('\n');
+        }
+        let len = self.file.metadata().map_err(technical)?.len();
+        if let Err(e) = self.file.write_all(out.as_bytes()).and_then(|_| self.file.sync_data()) {
+            // leave no partial line for the next write to follow
+            let _ = self.file.set_len(len);
+            return Err(technical(e));
+        }
+        for record in records {
+            self.take(record)?;
+        }
+
+        // the records are safely written, a compaction that fails is tried again on the next write
+        if self.superseded > self.compact_after.max(self.in_use()) {
+            if let Err(e) = self.compact() {
+                error!("{} not compacted: {:?}", self.path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// How many records are still in use
+    fn in_use(&self) -> usize {
+        self.events.values().map(|e| e.len()).sum::<usize>() + self.snapshots.len() + self.views.len()
+    }
+
+    /// Every record still in use, each aggregate's events together and in sequence,
+    /// the aggregates in key order rather than the order their events were committed
+    fn records(&self) -> Vec<Record> {
+        let events = self.events.iter().flat_map(|((aggregate_type, aggregate_id), events)| {
+            events.iter().map(move |e| Record::Event {
+                aggregate_type: aggregate_type.clone(),
+                aggregate_id: aggregate_id.clone(),
+                sequence: e.sequence,
+                payload: e.payload.clone(),
+                metadata: e.metadata.clone(),
+                time: e.timestamp.to_rfc3339(),
+            })
+        });
+        let snapshots = self.snapshots.iter().map(|((aggregate_type, aggregate_id), (last_sequence, payload))| Record::Snapshot {
+            aggregate_type: aggregate_type.clone(),
+            aggregate_id: aggregate_id.clone(),
+            last_sequence: *last_sequence,
+            payload: payload.clone(),
+        });
+        let views = self.views.iter().map(|((name, id), (version, payload))| Record::View {
+            name: name.clone(),
+            id: id.clone(),
+            version: *version,
+            payload: payload.clone(),
+        });
+        events.chain(snapshots).chain(views).collect()
+    }
+
+    /// Rewrites the file with only the records in use, swapping it in whole
+    fn compact(&mut self) -> Result<(), AggregateError> {
+        let mut compacted = self.path.clone().into_os_string();
+        compacted.push(".compact");
+        let compacted = PathBuf::from(compacted);
+
+        // left over from a compaction cut short
+        let _ = std::fs::remove_file(&compacted);
+        // opened for appending to, so once renamed it carries straight on as the store
+        let mut out = OpenOptions::new().create_new(true).read(true).append(true).open(&compacted).map_err(technical)?;
+        for record in self.records() {
+            let line = serde_json::to_string(&record).map_err(technical)?;
+            writeln!(out, "{}", line).map_err(technical)?;
+        }
+        out.sync_all().map_err(technical)?;
+        std::fs::rename(&compacted, &self.path).map_err(technical)?;
+
+        self.file = out;
+        self.superseded = 0;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct FileBackend {
+    db: Arc<Mutex<FileDb>>,
+}
+
+impl FileBackend {
+    /// The store in the file at `path`, created when there is none
+    pub fn open(path: &Path) -> Result<Self, AggregateError> {
+        let mut open = OPEN.lock().map_err(technical)?;
+        if let Some((_, db)) = open.iter().find(|(p, _)| p == path) {
+            return Ok(FileBackend { db: db.clone() });
+        }
+        let db = Arc::new(Mutex::new(FileDb::load(path)?));
+        open.push((path.to_path_buf(), db.clone()));
+        Ok(FileBackend { db })
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut FileDb) -> Result<T, AggregateError>) -> Result<T, AggregateError> {
+        let mut db = self.db.lock().map_err(technical)?;
+        f(&mut *db)
+    }
+}
+
+impl Backend for FileBackend {
+    fn events(&self, aggregate_type: &str, aggregate_id: &str, after: usize) -> Result<Vec<StoredEvent>, AggregateError> {
+        self.with(|db| Ok(db.events.get(&key(aggregate_type, aggregate_id))
+            .map(|events| events.iter().skip(after).cloned().collect())
+            .unwrap_or_default()))
+    }
+
+    fn events_as_of(&self, aggregate_type: &str, aggregate_id: &str, point: &PointInTime) -> Result<Vec<StoredEvent>, AggregateError> {
+        self.with(|db| Ok(db.events.get(&key(aggregate_type, aggregate_id))
+            .map(|events| events.iter()
+                .take_while(|e| match point {
+                    PointInTime::At(time)           => e.timestamp <= *time,
+                    PointInTime::Sequence(sequence) => e.sequence <= *sequence,
+                })
+                .cloned()
+                .collect())
+            .unwrap_or_default()))
+    }
+
+    fn append(&self, aggregate_type: &str, aggregate_id: &str, current: usize, events: &[StoredEvent]) -> Result<(), AggregateError> {
+        self.with(|db| {
+            let latest = db.events.get(&key(aggregate_type, aggregate_id)).map_or(0, |e| e.len());
+            if latest != current {
+                return Err(concurrent_update(aggregate_id, current));
+            }
+            let records = events.iter().enumerate().map(|(i, e)| Record::Event {
+                aggregate_type: String::from(aggregate_type),
+                aggregate_id: String::from(aggregate_id),
+                sequence: current + i + 1,
+                payload: e.payload.clone(),
+                metadata: e.metadata.clone(),
+                time: e.timestamp.to_rfc3339(),
+            }).collect();
+            db.write(records)
+        })
+    }
+
+    fn aggregate_ids(&self, aggregate_type: &str) -> Result<Vec<String>, AggregateError> {
+        self.with(|db| Ok(db.events.keys()
+            .filter(|(t, _)| t == aggregate_type)
+            .map(|(_, id)| id.clone())
+            .collect()))
+    }
+
+    fn snapshot(&self, aggregate_type: &str, aggregate_id: &str) -> Result<Option<(usize, Value)>, AggregateError> {
+        self.with(|db| Ok(db.snapshots.get(&key(aggregate_type, aggregate_id)).cloned()))
+    }
+
+    fn save_snapshot(&self, aggregate_type: &str, aggregate_id: &str, sequence: usize, payload: &Value) -> Result<(), AggregateError> {
+        self.with(|db| db.write(vec![Record::Snapshot {
+            aggregate_type: String::from(aggregate_type),
+            aggregate_id: String::from(aggregate_id),
+            last_sequence: sequence,
+            payload: payload.clone(),
+        }]))
+    }
+
+    fn view(&self, name: &str, id: &str) -> Result<Option<(usize, Value)>, AggregateError> {
+        self.with(|db| Ok(db.views.get(&key(name, id)).cloned()))
+    }
+
+    fn save_view(&self, name: &str, id: &str, loaded: usize, version: usize, payload: &Value) -> Result<(), AggregateError> {
+        self.with(|db| {
+            let stored = db.views.get(&key(name, id)).map_or(0, |(v, _)| *v);
+            if stored != loaded {
+                return Err(concurrent_update(id, loaded));
+            }
+            db.write(vec![Record::View { name: String::from(name), id: String::from(id), version, payload: payload.clone() }])
+        })
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("esipam-{}.db", Uuid::new_v4()))
+    }
+
+    fn remove(path: &Path) {
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(lock_path(path)).unwrap();
+    }
+
+    fn unshared(path: &Path) -> FileBackend {
+        FileBackend { db: Arc::new(Mutex::new(FileDb::load(path).unwrap())) }
+    }
+
+    fn event(n: u64) -> StoredEvent {
+        StoredEvent { sequence: 0, payload: serde_json::json!({ "n": n }), metadata: HashMap::new(), timestamp: Utc::now() }
+    }
+
+    #[test]
+    fn test_append_is_refused_after_another_commit() {
+        let path = temp_path();
+        let store = unshared(&path);
+        store.append("Ipam", "a", 0, &[event(1), event(2)]).unwrap();
+        store.append("Ipam", "b", 0, &[event(1)]).unwrap();
+
+        // both loaded "a" at 2, only the first may follow it
+        store.append("Ipam", "a", 2, &[event(3)]).unwrap();
+        let lost = store.append("Ipam", "a", 2, &[event(3)]);
+        match lost {
+            Err(AggregateError::UserError(payload)) => assert_eq!(payload.code.as_deref(), Some("ConcurrentUpdate")),
+            other => panic!("expected a ConcurrentUpdate, got {:?}", other),
+        }
+
+        let events = store.events("Ipam", "a", 1).unwrap();
+        assert_eq!(events.iter().map(|e| e.sequence).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(store.aggregate_ids("Ipam").unwrap(), vec!["a", "b"]);
+        remove(&path);
+    }
+
+    #[test]
+    fn test_events_as_of_stop_at_the_point() {
+        let path = temp_path();
+        let store = unshared(&path);
+        let start = Utc::now();
+        let at = |n: u64| StoredEvent { timestamp: start + chrono::Duration::seconds(n as i64), ..event(n) };
+        store.append("Ipam", "a", 0, &[at(1), at(2), at(3)]).unwrap();
+
+        let sequences = |point: PointInTime| -> Vec<usize> {
+            store.events_as_of("Ipam", "a", &point).unwrap().iter().map(|e| e.sequence).collect()
+        };
+        assert_eq!(sequences(PointInTime::Sequence(2)), vec![1, 2]);
+        assert_eq!(sequences(PointInTime::Sequence(9)), vec![1, 2, 3]);
+        assert_eq!(sequences(PointInTime::At(start + chrono::Duration::seconds(2))), vec![1, 2]);
+        assert!(sequences(PointInTime::At(start)).is_empty());
+        drop(store);
+        remove(&path);
+    }
+
+    #[test]
+    fn test_reopen_keeps_the_latest() {
+        let path = temp_path();
+        let store = unshared(&path);
+        store.append("Ipam", "a", 0, &[event(1), event(2)]).unwrap();
+        store.save_snapshot("Ipam", "a", 1, &serde_json::json!("one")).unwrap();
+        store.save_snapshot("Ipam", "a", 2, &serde_json::json!("two")).unwrap();
+        store.save_view("ipam_query", "a", 0, 1, &serde_json::json!("v1")).unwrap();
+        store.save_view("ipam_query", "a", 1, 2, &serde_json::json!("v2")).unwrap();
+        assert!(store.save_view("ipam_query", "a", 1, 2, &serde_json::json!("stale")).is_err());
+
+        // a write cut short is dropped along with the records replaced
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"record\":\"ev").unwrap();
+        drop(store);
+
+        let store = unshared(&path);
+        assert_eq!(store.events("Ipam", "a", 0).unwrap().len(), 2);
+        assert_eq!(store.snapshot("Ipam", "a").unwrap(), Some((2, serde_json::json!("two"))));
+        assert_eq!(store.view("ipam_query", "a").unwrap(), Some((2, serde_json::json!("v2"))));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+        remove(&path);
+    }
+
+    #[test]
+    fn test_replaced_views_are_compacted_while_open() {
+        let path = temp_path();
+        let store = unshared(&path);
+        store.append("Ipam", "a", 0, &[event(1)]).unwrap();
+        store.with(|db| {
+            db.compact_after = 10;
+            Ok(())
+        }).unwrap();
+
+        for version in 0..100 {
+            store.save_view("ipam_query", "a", version, version + 1, &serde_json::json!(version)).unwrap();
+            // the event and the view in use, and no more than 10 they replaced
+            assert!(std::fs::read_to_string(&path).unwrap().lines().count() <= 12);
+        }
+        drop(store);
+
+        let store = unshared(&path);
+        assert_eq!(store.events("Ipam", "a", 0).unwrap().len(), 1);
+        assert_eq!(store.view("ipam_query", "a").unwrap(), Some((100, serde_json::json!(99))));
+        remove(&path);
+    }
+
+    #[test]
+    fn test_open_file_is_refused_to_another() {
+        let path = temp_path();
+        let store = unshared(&path);
+        match FileDb::load(&path) {
+            Err(AggregateError::TechnicalError(msg)) => assert!(msg.contains("open in another esipam process")),
+            other => panic!("expected the file to be refused, got {:?}", other.map(|_| ())),
+        }
+        drop(store);
+        assert!(FileDb::load(&path).is_ok());
+        remove(&path);
+    }
+}
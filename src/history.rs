@@ -0,0 +1,286 @@
+//! The audit history of every entry an Ipam has held, kept as a query
+//! projection so a timeline is a single read rather than a replay.
+//!
+//! Each step records what changed, the event sequence number, and the time
+//! and identity `process_command` put in the event metadata.
+//!
+//! ```text
+//!  10.1.2.0/24  created     #4   2023-05-01T09:12:00Z
+//!               attribute+  #5   env=prod
+//!               resized     #9   10.1.2.0/24 -> 10.1.2.0/23
+//!               released    #12
+//! ```
+
+use cqrs_es::{EventEnvelope, Query};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+use crate::attributes::Attributes;
+use crate::events::IpamEvent;
+use crate::ipam_model::{CidrEntry, CidrId, EntryState, Ipam, Label};
+
+/// One change in the life of an entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// added to the Ipam; `from` names the entries it was split or merged from
+    Created {
+        state: EntryState,
+        attributes: Attributes,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        from: Vec<IpNetwork>,
+    },
+    Reparented { parent: Option<CidrId> },
+    AttributeAdded { attribute: Label },
+    AttributeRemoved { attribute: Label },
+    StateChanged { from: EntryState, to: EntryState },
+    Paired { with: CidrId },
+    Unpaired,
+    Resized { from: IpNetwork, to: IpNetwork },
+    SplitInto { cidrs: Vec<IpNetwork> },
+    MergedInto { cidr: IpNetwork },
+    Released,
+    /// the released space is held back from allocation until `until`, seconds since the epoch
+    Quarantined { until: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryStep {
+    pub sequence: usize,
+    pub time: Option<String>,
+    pub who: Option<String>,
+    /// the entry's CIDR when the change was made
+    pub cidr: IpNetwork,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+/// The timeline of a single entry, oldest step first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntryHistory {
+    pub uuid: Uuid,
+    pub id: CidrId,
+    pub sysref: Option<String>,
+    pub cidr: IpNetwork,
+    pub steps: Vec<HistoryStep>,
+}
+
+/// A stretch of time an entry held a CIDR, open ended while it still does
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Holding {
+    pub uuid: Uuid,
+    pub id: CidrId,
+    pub sysref: Option<String>,
+    pub cidr: IpNetwork,
+    pub from: Option<String>,
+    pub until: Option<String>,
+}
+
+/// Every entry the Ipam has held, released ones included, by uuid, with the
+/// ids and sysrefs they are looked up by
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IpamHistoryView {
+    pub entries: HashMap<Uuid, EntryHistory>,
+    ids: HashMap<CidrId, Uuid>,
+    sysrefs: HashMap<String, Vec<Uuid>>,
+}
+
+impl IpamHistoryView {
+    /// The timeline of an entry named by its id, uuid or sysref. An id or
+    /// sysref used more than once over time finds the latest entry to use it.
+    pub fn find(&self, name: &str) -> Option<&EntryHistory> {
+        let uuid = self.ids.get(&Box::new(String::from(name))).copied()
+            .or_else(|| name.parse::<Uuid>().ok().filter(|u| self.entries.contains_key(u)))
+            .or_else(|| self.sysrefs.get(name).and_then(|u| u.last().copied()))?;
+        self.entries.get(&uuid)
+    }
+
+    /// Every entry ever given `sysref`, oldest first
+    pub fn by_sysref(&self, sysref: &str) -> Vec<&EntryHistory> {
+        self.sysrefs.get(sysref)
+            .map(|uuids| uuids.iter().filter_map(|u| self.entries.get(u)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Every entry that has held `addr`, and when, oldest first. An entry with
+    /// host bits set (10.99.99.68/24) only ever holds its own address.
+    pub fn holders_of(&self, addr: IpAddr) -> Vec<Holding> {
+        let holds = |cidr: &IpNetwork| if cidr.ip() == cidr.network() { cidr.contains(addr) } else { cidr.ip() == addr };
+        let mut found: Vec<(usize, Holding)> = vec![];
+        for history in self.entries.values() {
+            let mut open: Option<(usize, IpNetwork, Option<String>)> = None;
+            for step in history.steps.iter() {
+                let (closes, opens) = match &step.change {
+                    Change::Created { .. } => (false, Some(step.cidr)),
+                    Change::Resized { to, .. } => (true, Some(*to)),
+                    Change::Released | Change::SplitInto { .. } | Change::MergedInto { .. } => (true, None),
+                    _ => (false, None),
+                };
+                if closes {
+                    if let Some((sequence, cidr, from)) = open.take() {
+                        found.push((sequence, history.holding(cidr, from, step.time.clone())));
+                    }
+                }
+                if let Some(cidr) = opens {
+                    open = Some((step.sequence, cidr, step.time.clone()));
+                }
+            }
+            if let Some((sequence, cidr, from)) = open {
+                found.push((sequence, history.holding(cidr, from, None)));
+            }
+        }
+        found.retain(|(_, h)| holds(&h.cidr));
+        found.sort_by_key(|(sequence, _)| *sequence);
+        found.into_iter().map(|(_, h)| h).collect()
+    }
+
+    fn created(&mut self, entry: &CidrEntry, from: Vec<IpNetwork>, event: &EventEnvelope<Ipam, IpamEvent>) {
+        let history = EntryHistory {
+            uuid: entry.uuid,
+            id: entry.id.clone(),
+            sysref: entry.sysref.clone(),
+            cidr: entry.cidr,
+            steps: vec![],
+        };
+        self.ids.insert(entry.id.clone(), entry.uuid);
+        if let Some(sysref) = &entry.sysref {
+            self.sysrefs.entry(sysref.clone()).or_default().push(entry.uuid);
+        }
+        self.entries.insert(entry.uuid, history);
+        let change = Change::Created { state: entry.state, attributes: entry.attributes.clone(), from };
+        self.record(&entry.id, change, event);
+    }
+
+    fn record(&mut self, id: &CidrId, change: Change, event: &EventEnvelope<Ipam, IpamEvent>) {
+        let uuid = match self.ids.get(id) {
+            Some(uuid) => *uuid,
+            None => return,
+        };
+        let history = match self.entries.get_mut(&uuid) {
+            Some(history) => history,
+            None => return,
+        };
+        if let Change::Resized { to, .. } = &change {
+            history.cidr = *to;
+        }
+        history.steps.push(HistoryStep {
+            sequence: event.sequence,
+            time: event.metadata.get("time").cloned(),
+            who: event.metadata.get("identity").cloned(),
+            cidr: history.cidr,
+            change,
+        });
+    }
+}
+
+impl EntryHistory {
+    fn holding(&self, cidr: IpNetwork, from: Option<String>, until: Option<String>) -> Holding {
+        Holding { uuid: self.uuid, id: self.id.clone(), sysref: self.sysref.clone(), cidr, from, until }
+    }
+}
+
+impl Query<Ipam, IpamEvent> for IpamHistoryView {
+    fn update(&mut self, event: &EventEnvelope<Ipam, IpamEvent>) {
+        match &event.payload {
+            IpamEvent::IpamCreated(_) => {},
+            IpamEvent::AttributeSchemaChanged(_) => {},
+            IpamEvent::CidrEntryAdded(p) => self.created(&p.cidr_entry, vec![], event),
+            IpamEvent::CidrEntryReparented(p) => {
+                self.record(&p.id, Change::Reparented { parent: p.parent.clone() }, event)
+            },
+            IpamEvent::CidrEntryReleased(p) => self.record(&p.cidr_entry.id, Change::Released, event),
+            IpamEvent::CidrAttributeAdded(p) => {
+                self.record(&p.id, Change::AttributeAdded { attribute: p.attribute.clone() }, event)
+            },
+            IpamEvent::CidrAttributeRemoved(p) => {
+                self.record(&p.id, Change::AttributeRemoved { attribute: p.attribute.clone() }, event)
+            },
+            IpamEvent::CidrEntriesPaired(p) => {
+                self.record(&p.v4, Change::Paired { with: p.v6.clone() }, event);
+                self.record(&p.v6, Change::Paired { with: p.v4.clone() }, event);
+            },
+            IpamEvent::CidrEntriesUnpaired(p) => {
+                self.record(&p.v4, Change::Unpaired, event);
+                self.record(&p.v6, Change::Unpaired, event);
+            },
+            IpamEvent::CidrEntryStateChanged(p) => {
+                self.record(&p.id, Change::StateChanged { from: p.from, to: p.to }, event)
+            },
+            IpamEvent::CidrBlockQuarantined(p) => self.record(&p.id, Change::Quarantined { until: p.until }, event),
+            IpamEvent::CidrEntrySplit(p) => {
+                let cidrs = p.parts.iter().map(|part| part.cidr).collect();
+                self.record(&p.cidr_entry.id, Change::SplitInto { cidrs }, event);
+                for part in p.parts.iter() {
+                    self.created(part, vec![p.cidr_entry.cidr], event);
+                }
+            },
+            IpamEvent::CidrEntriesMerged(p) => {
+                for entry in p.cidr_entries.iter() {
+                    self.record(&entry.id, Change::MergedInto { cidr: p.into.cidr }, event);
+                }
+                self.created(&p.into, p.cidr_entries.iter().map(|e| e.cidr).collect(), event);
+            },
+            IpamEvent::CidrEntryResized(p) => {
+                self.record(&p.id, Change::Resized { from: p.from, to: p.to }, event)
+            },
+        }
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::events::{CidrAttributeAdded, CidrEntryAdded, CidrEntryReleased, CidrEntryResized};
+
+    fn envelope(sequence: usize, payload: IpamEvent) -> EventEnvelope<Ipam, IpamEvent> {
+        let mut metadata = HashMap::new();
+        metadata.insert(String::from("time"), format!("2023-05-0{}T00:00:00Z", sequence));
+        metadata.insert(String::from("identity"), String::from("ops"));
+        EventEnvelope::new_with_metadata(String::from("ipam_1"), sequence, String::from("Ipam"), payload, metadata)
+    }
+
+    fn entry(cidr: &str, sysref: &str) -> CidrEntry {
+        let mut entry = CidrEntry::from(cidr.parse::<IpNetwork>().unwrap());
+        entry.sysref = Some(String::from(sysref));
+        entry
+    }
+
+    #[test]
+    fn test_entry_timeline() {
+        let mut view = IpamHistoryView::default();
+        let first = entry("10.1.2.0/24", "vlan::100");
+        view.update(&envelope(1, IpamEvent::CidrEntryAdded(CidrEntryAdded { cidr_entry: first.clone() })));
+        view.update(&envelope(2, IpamEvent::CidrAttributeAdded(CidrAttributeAdded {
+            id: first.id.clone(), cidr: first.cidr, attribute: Label::new("env", "prod") })));
+        view.update(&envelope(3, IpamEvent::CidrEntryResized(CidrEntryResized {
+            id: first.id.clone(), from: first.cidr, to: "10.1.2.0/23".parse().unwrap() })));
+        view.update(&envelope(4, IpamEvent::CidrEntryReleased(CidrEntryReleased { cidr_entry: first.clone() })));
+
+        let history = view.find(&first.id).unwrap();
+        let changes: Vec<(usize, String)> = history.steps.iter().map(|s| (s.sequence, s.cidr.to_string())).collect();
+        assert_eq!(changes, vec![(1, "10.1.2.0/24".into()), (2, "10.1.2.0/24".into()), (3, "10.1.2.0/23".into()), (4, "10.1.2.0/23".into())]);
+        assert_eq!(history.steps[3].change, Change::Released);
+        assert_eq!(history.steps[3].who.as_deref(), Some("ops"));
+        assert_eq!(view.find(&first.uuid.to_string()), Some(history));
+
+        // the sysref is used again by a later entry
+        let second = entry("10.1.3.0/24", "vlan::100");
+        view.update(&envelope(5, IpamEvent::CidrEntryAdded(CidrEntryAdded { cidr_entry: second.clone() })));
+        assert_eq!(view.find("vlan::100").map(|h| h.uuid), Some(second.uuid));
+        assert_eq!(view.by_sysref("vlan::100").len(), 2);
+
+        let holders = view.holders_of("10.1.3.9".parse().unwrap());
+        let held: Vec<(String, Option<String>, Option<String>)> = holders.iter()
+            .map(|h| (h.cidr.to_string(), h.from.clone(), h.until.clone()))
+            .collect();
+        assert_eq!(held, vec![
+            (String::from("10.1.2.0/23"), Some(String::from("2023-05-03T00:00:00Z")), Some(String::from("2023-05-04T00:00:00Z"))),
+            (String::from("10.1.3.0/24"), Some(String::from("2023-05-05T00:00:00Z")), None),
+        ]);
+    }
+}
@@ -1,25 +1,38 @@
 use cqrs_es::Aggregate;
 use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::mem;
 use std::net::{ IpAddr , Ipv4Addr, Ipv6Addr };
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
+use crate::attributes::{AttributeValue, Attributes};
 use crate::error::IpamError;
+use crate::prefix_trie::{range_prefixes, PrefixKey, PrefixTrie};
+use crate::schema::{AttributeSchema, Violation};
+use crate::search::SearchExpr;
 
 /* --- Common and Simple Types -----------------------------------------*/
 
-#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Label {
-    key: String,
-    value: String,
+    pub key: String,
+    pub value: AttributeValue,
+}
+
+impl Label {
+    pub fn new(key: &str, value: &str) -> Self {
+        Label { key: String::from(key), value: AttributeValue::from(value) }
+    }
 }
 
 #[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub enum IPProtocolFamily {
     V4,
     V6,
+    /// both V4 and V6 entries, each family in its own hierarchy
+    Dual,
 }
 
 impl Default for IPProtocolFamily {
@@ -28,7 +41,7 @@ impl Default for IPProtocolFamily {
     }
 }
 
-type CidrId = Box<String>;
+pub type CidrId = Box<String>;
 
 
 /* --- Ipam -----------------------------------------*/
@@ -39,7 +52,7 @@ type CidrId = Box<String>;
 /// ,---------.
 /// |  Ipam   |
 /// |---------| Holds 1 Ipam set of CIDRs, a routing domain or 
-/// |         | a logical non-duplicate CIDR tree. pinned at v4 or v6
+/// |         | a logical non-duplicate CIDR tree. pinned at v4 or v6, or dual-stack
 /// `----1----'
 ///      |     
 /// ,----*----.
@@ -48,7 +61,7 @@ type CidrId = Box<String>;
 /// `----1----'
 ///      |               
 ///   ,--*--.  
-///   |Label|  <-- many labels for a CidrEntry
+///   |Label|  <-- many labels for a CidrEntry, one per key
 ///   |-----|  
 ///   `-----'  
 /// ```
@@ -68,12 +81,63 @@ type CidrId = Box<String>;
 ///
 
 #[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "IpamData")]
 pub struct Ipam {
     pub id: String,
     pub uuid: Uuid,
     pub protocol: IPProtocolFamily,
     pub cidrs: Vec<CidrEntry>,
     pub cfg: Option<IpamConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<AttributeSchema>,
+    /// Released blocks held back from allocation until their quarantine runs out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quarantine: Vec<Quarantined>,
+    #[serde(skip)]
+    index: CidrIndex,
+}
+
+/// The persisted shape of an Ipam; the index is rebuilt from `cidrs` on load
+#[derive(Deserialize)]
+struct IpamData {
+    id: String,
+    uuid: Uuid,
+    protocol: IPProtocolFamily,
+    cidrs: Vec<CidrEntry>,
+    cfg: Option<IpamConfig>,
+    #[serde(default)]
+    schema: Option<AttributeSchema>,
+    #[serde(default)]
+    quarantine: Vec<Quarantined>,
+}
+
+impl From<IpamData> for Ipam {
+    fn from(data: IpamData) -> Ipam {
+        let mut ipam = Ipam {
+            id: data.id,
+            uuid: data.uuid,
+            protocol: data.protocol,
+            cidrs: data.cidrs,
+            cfg: data.cfg,
+            schema: data.schema,
+            quarantine: data.quarantine,
+            index: Default::default(),
+        };
+        ipam.reindex();
+        ipam
+    }
+}
+
+/// Lookup structures over `Ipam.cidrs`.
+///
+/// Each trie node holds the positions (in `cidrs`) of the entries whose network
+/// is that prefix. More than one entry can share a node, as `10.2.0.0/21`
+/// and `10.2.2.1/21` both live at `10.2.0.0/21`.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+struct CidrIndex {
+    v4: PrefixTrie<Vec<usize>>,
+    v6: PrefixTrie<Vec<usize>>,
+    ids: HashMap<CidrId, usize>,
 }
 
 impl Default for Ipam {
@@ -83,24 +147,110 @@ impl Default for Ipam {
             uuid: Uuid::new_v4(),
             protocol: Default::default(),
             cidrs: Default::default(),
-            cfg: None
+            cfg: None,
+            schema: None,
+            quarantine: vec![],
+            index: Default::default(),
+        }
+    }
+}
+
+/// The trie key for a network, ignoring any host bits
+pub(crate) fn prefix_key(cidr: &IpNetwork) -> PrefixKey {
+    match cidr {
+        IpNetwork::V4(v4) => PrefixKey::new((u32::from(v4.ip()) as u128) << 96, v4.prefix()),
+        IpNetwork::V6(v6) => PrefixKey::new(u128::from(v6.ip()), v6.prefix()),
+    }
+}
+
+/// The network for a trie key, in the same protocol family as `like`
+pub(crate) fn network_of(key: PrefixKey, like: &IpNetwork) -> IpNetwork {
+    match like {
+        IpNetwork::V4(_) => IpNetwork::V4(
+            Ipv4Network::new(Ipv4Addr::from((key.bits >> 96) as u32), key.len).expect("prefix fits v4")),
+        IpNetwork::V6(_) => IpNetwork::V6(
+            Ipv6Network::new(Ipv6Addr::from(key.bits), key.len).expect("prefix fits v6")),
+    }
+}
+
+/// The address space an entry takes up. A network entry uses its whole block,
+/// whereas an entry with host bits set (10.99.99.68/24) is a single address.
+fn occupied_key(cidr: &IpNetwork) -> PrefixKey {
+    match cidr {
+        IpNetwork::V4(v4) if v4.ip() != v4.network() => prefix_key(&IpNetwork::V4(Ipv4Network::from(v4.ip()))),
+        IpNetwork::V6(v6) if v6.ip() != v6.network() => prefix_key(&IpNetwork::V6(Ipv6Network::from(v6.ip()))),
+        _ => prefix_key(cidr),
+    }
+}
+
+/// Seconds since the unix epoch, the clock quarantine is measured against
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// The blocks of `prefix_len` making up `cidr`, in address order.
+/// The caller keeps `prefix_len` to something sensible.
+pub(crate) fn subnets_of(cidr: &IpNetwork, prefix_len: u8) -> Vec<IpNetwork> {
+    let key = prefix_key(cidr);
+    let step = 1u128 << (128 - prefix_len as u32);
+    let count = 1u128 << (prefix_len - cidr.prefix()) as u32;
+    (0..count)
+        .map(|i| network_of(PrefixKey::new(key.first() + i * step, prefix_len), cidr))
+        .collect()
+}
+
+/// The single network exactly covering `cidrs`, so long as they run on
+/// from one another and together line up on a prefix boundary
+pub(crate) fn supernet_of(cidrs: &[IpNetwork]) -> Result<IpNetwork, IpamError> {
+    let like = cidrs.first().ok_or_else(|| IpamError::BadRequest(String::from("nothing to merge")))?;
+    let mut keys: Vec<PrefixKey> = cidrs.iter().map(prefix_key).collect();
+    keys.sort();
+    for pair in keys.windows(2) {
+        if pair[0].last().checked_add(1) != Some(pair[1].first()) {
+            return Err(IpamError::BadRequest(format!("{} and {} are not contiguous",
+                network_of(pair[0], like), network_of(pair[1], like))))
         }
     }
+
+    let (first, last) = (keys[0], keys[keys.len() - 1]);
+    match range_prefixes(first.first(), last.last()).as_slice() {
+        [one] => Ok(network_of(*one, like)),
+        _ => Err(IpamError::BadRequest(format!("{} to {} does not line up on a single prefix",
+            network_of(first, like), network_of(last, like)))),
+    }
+}
+
+/// The number of /unit blocks in a /prefix_len
+fn units_in(prefix_len: u8, unit: u8) -> u128 {
+    if prefix_len > unit {
+        0
+    } else if unit - prefix_len >= 128 {
+        u128::MAX
+    } else {
+        1u128 << (unit - prefix_len)
+    }
+}
+
+fn max_prefix(cidr: &IpNetwork) -> u8 {
+    match cidr {
+        IpNetwork::V4(_) => 32,
+        IpNetwork::V6(_) => 128,
+    }
 }
 
-trait Finder<T> {
+pub(crate) trait Finder<T> {
     fn find(&self, search: T) -> Option<CidrEntry>;
 }
 
 impl Finder<&CidrId> for Ipam {
     fn find(&self, search: &CidrId) -> Option<CidrEntry> {
-        self.cidrs.iter().find(|&ce| ce.id == *search).map(|r| r.clone())
+        self.index.ids.get(search).map(|&i| self.cidrs[i].clone())
     }
 }
 
 impl Finder<&IpNetwork> for Ipam {
     fn find(&self, search: &IpNetwork) -> Option<CidrEntry> {
-        self.cidrs.iter().find(|&ce| ce.cidr == *search).map(|r| r.clone())
+        self.position_of(search).map(|i| self.cidrs[i].clone())
     }
 }
 
@@ -110,6 +260,15 @@ impl Finder<&Uuid> for Ipam {
     }
 }
 
+/// An exact match on an entry's sysref, rather than the loose `&str` search
+pub struct SysRef<'a>(pub &'a str);
+
+impl Finder<SysRef<'_>> for Ipam {
+    fn find(&self, search: SysRef) -> Option<CidrEntry> {
+        self.cidrs.iter().find(|&ce| ce.sysref.as_deref() == Some(search.0)).map(|r| r.clone())
+    }
+}
+
 impl Finder<&str> for Ipam {
     fn find(&self, search: &str) -> Option<CidrEntry> {
         self.cidrs.iter().find(|&ce| 
@@ -117,7 +276,7 @@ impl Finder<&str> for Ipam {
                    search if ce.cidr.to_string().contains(search) => true,
                    search if ce.id.to_string().contains(search) => true,
                    search if ce.uuid.to_string().contains(search) => true,
-                   search if ce.sysref.as_ref().unwrap().contains(search) => true,
+                   search if ce.sysref.as_ref().map_or(false, |r| r.contains(search)) => true,
                    _ => false
 
                })
@@ -141,8 +300,33 @@ impl Ipam {
         }
     }
 
-    pub (crate) fn children_of(&self, entry: IpNetwork) -> Vec<IpNetwork> {
-        vec![]
+    /// The entries sitting directly beneath `entry`; those whose closest enclosing
+    /// network entry is (or would be, were it added) `entry`.
+    ///
+    /// Only network entries have children, so an entry with host bits set has none.
+    pub (crate) fn children_of(&self, entry: IpNetwork) -> Vec<CidrEntryResult> {
+        let mut results = vec![];
+        if entry.ip() != entry.network() {
+            return results;
+        }
+
+        let key = prefix_key(&entry);
+        self.trie(&entry).walk(&key, |k, slot| {
+            match self.anchor(slot) {
+                // a closer network entry, everything below it is its own
+                Some(i) if k != key => {
+                    results.push(self.result_at(i));
+                    false
+                }
+                _ => {
+                    results.extend(slot.iter()
+                        .filter(|&&i| !self.cidrs[i].is_canonical())
+                        .map(|&i| self.result_at(i)));
+                    true
+                }
+            }
+        });
+        results
     }
 
     pub fn filter(&self, search: &str) -> Vec<&CidrEntry> {
@@ -151,105 +335,293 @@ impl Ipam {
                     search if ce.cidr.to_string().contains(search) => true,
                     search if ce.id.to_string().contains(search) => true,
                     search if ce.uuid.to_string().contains(search) => true,
-                    search if ce.sysref.as_ref().unwrap().contains(search) => true,
+                    search if ce.sysref.as_ref().map_or(false, |r| r.contains(search)) => true,
                     _ => false
 
                 }).collect()
     }
-    
 
-    /// Returns the ID, and the CIDR of the located entry
-    /// need to optimise this (Tuple struct or something, to pin the type)
-    pub (crate) fn parent_of(&self, entry: IpNetwork) -> Option<CidrEntryResult> {
-        match entry { 
-            IpNetwork::V6(v6) => {
-                for (i, e) in self.cidrs.iter().enumerate() {
-                    let candidate = match e.cidr {
-                        IpNetwork::V6(x) => Ok(x),
-                        _ => Err(IpamError::InvalidProtocol),
-                    }
-                    .expect("Dead code, can't get here");
-
-                    if v6 != candidate && v6.is_subnet_of(candidate) {
-                        return Some(CidrEntryResult{ id: e.id.clone(), cidr: e.cidr})
-                    }
-                }
-            },
-            IpNetwork::V4(v4) => {
-                for (i, e) in self.cidrs.iter().enumerate() {
-                    let candidate = match e.cidr {
-                        IpNetwork::V4(x) => Ok(x),
-                        _ => Err(IpamError::InvalidProtocol),
-                    }
-                    .expect("Dead code, can't get here");
-
-                    if v4 != candidate && v4.is_subnet_of(candidate) {
-                        return Some(CidrEntryResult{ id: e.id.clone(), cidr: e.cidr })
-                    }
-                }
+    /// The entries matching a query, see `crate::search` for the language
+    ///
+    /// `within:10.0.0.0/8 AND prefixlen>=24 AND NOT label:env=prod`
+    pub fn search(&self, query: &str) -> Result<Vec<&CidrEntry>, IpamError> {
+        let expr: SearchExpr = query.parse()?;
+        Ok(self.matching(&expr))
+    }
+
+    pub fn matching(&self, expr: &SearchExpr) -> Vec<&CidrEntry> {
+        let effective = expr.needs_effective();
+        self.cidrs.iter()
+            .filter(|&ce| if effective {
+                expr.matches(ce, Some(&self.effective_attributes(ce)))
+            } else {
+                expr.matches(ce, None)
+            })
+            .collect()
+    }
+
+    /// The attributes an entry ends up with: those of its ancestors, from the
+    /// top down, overridden by its own. Keys the schema marks as not inherited
+    /// stay with the entry holding them.
+    pub fn effective_attributes(&self, entry: &CidrEntry) -> Attributes {
+        let mut ancestors = vec![];
+        let mut parent = entry.parent.as_ref().and_then(|p| self.index.ids.get(p));
+        while let Some(&i) = parent {
+            // the parent chain is a tree, so this only guards against bad data
+            if ancestors.len() > self.cidrs.len() {
+                break;
             }
+            ancestors.push(&self.cidrs[i]);
+            parent = self.cidrs[i].parent.as_ref().and_then(|p| self.index.ids.get(p));
+        }
+
+        let inherits = |key: &str| self.schema.as_ref().map_or(true, |s| s.inherits(key));
+        let mut effective = Attributes::default();
+        for ancestor in ancestors.iter().rev() {
+            for (key, value) in ancestor.attributes.iter().filter(|(k, _)| inherits(k)) {
+                effective.insert(Label { key: key.clone(), value: value.clone() });
+            }
+        }
+        for (key, value) in entry.attributes.iter() {
+            effective.insert(Label { key: key.clone(), value: value.clone() });
+        }
+        effective
+    }
+
+    fn trie(&self, cidr: &IpNetwork) -> &PrefixTrie<Vec<usize>> {
+        match cidr {
+            IpNetwork::V4(_) => &self.index.v4,
+            IpNetwork::V6(_) => &self.index.v6,
+        }
+    }
+
+    fn trie_mut(&mut self, cidr: &IpNetwork) -> &mut PrefixTrie<Vec<usize>> {
+        match cidr {
+            IpNetwork::V4(_) => &mut self.index.v4,
+            IpNetwork::V6(_) => &mut self.index.v6,
+        }
+    }
+
+    /// The entry at a trie node that other entries hang off; the one holding
+    /// the bare network address, if present.
+    fn anchor(&self, slot: &[usize]) -> Option<usize> {
+        slot.iter().copied().find(|&i| self.cidrs[i].is_canonical())
+    }
+
+    fn position_of(&self, cidr: &IpNetwork) -> Option<usize> {
+        self.trie(cidr)
+            .get(&prefix_key(cidr))
+            .and_then(|slot| slot.iter().copied().find(|&i| self.cidrs[i].cidr == *cidr))
+    }
+
+    /// Rebuild the lookup index from scratch
+    fn reindex(&mut self) {
+        self.index = Default::default();
+        for i in 0..self.cidrs.len() {
+            self.index_insert(i);
+        }
+    }
 
+    fn index_insert(&mut self, idx: usize) {
+        let cidr = self.cidrs[idx].cidr;
+        let id = self.cidrs[idx].id.clone();
+        let key = prefix_key(&cidr);
+        let trie = self.trie_mut(&cidr);
+        match trie.get_mut(&key) {
+            Some(slot) => slot.push(idx),
+            None => {
+                trie.insert(key, vec![idx]);
+            }
         }
-        None
+        self.index.ids.insert(id, idx);
+    }
+
+    fn index_remove(&mut self, idx: usize) {
+        let cidr = self.cidrs[idx].cidr;
+        let id = self.cidrs[idx].id.clone();
+        let key = prefix_key(&cidr);
+        let trie = self.trie_mut(&cidr);
+        let emptied = match trie.get_mut(&key) {
+            Some(slot) => {
+                slot.retain(|&i| i != idx);
+                slot.is_empty()
+            }
+            None => false,
+        };
+        if emptied {
+            trie.remove(&key);
+        }
+        self.index.ids.remove(&id);
+    }
+
+    /// Returns the ID, and the CIDR of the located entry
+    /// 
+    /// The parent is the closest enclosing network entry. An entry with host bits
+    /// set (10.2.2.1/21) sits beneath the network entry of the same prefix (10.2.0.0/21).
+    pub (crate) fn parent_of(&self, entry: IpNetwork) -> Option<CidrEntryResult> {
+        let key = prefix_key(&entry);
+        let canonical = entry.ip() == entry.network();
+
+        self.trie(&entry)
+            .ancestors(&key)
+            .into_iter()
+            .rev()
+            .filter(|(k, _)| k.len < key.len || !canonical)
+            .find_map(|(_, slot)| self.anchor(slot))
+            .map(|i| self.result_at(i))
+    }
+
+    fn result_at(&self, idx: usize) -> CidrEntryResult {
+        CidrEntryResult{ id: self.cidrs[idx].id.clone(), cidr: self.cidrs[idx].cidr }
     }
 
     pub(crate) fn add_entry(&mut self, entry: CidrEntry) -> Result<CidrEntry, IpamError> {
         // ensure the entry being added is matching the configured Ipam Protocol
-        match (self.protocol.clone(), entry.cidr) {
-            (IPProtocolFamily::V4, IpNetwork::V6(_)) => Err(IpamError::InvalidProtocol),
-            (IPProtocolFamily::V6, IpNetwork::V4(_)) => Err(IpamError::InvalidProtocol),
-            (IPProtocolFamily::V4, IpNetwork::V4(v4)) => {
-                let mut c = entry;
-                let cidrs = self.cidrs.clone();
-
-                for (i, e) in cidrs.iter().enumerate() {
-                    let candidate = match e.cidr {
-                        IpNetwork::V4(x) => Ok(x),
-                        _ => Err(IpamError::InvalidProtocol),
-                    }
-                    .expect("Dead code, can't get here");
-
-                    if v4.is_subnet_of(candidate) {
-                        c.parent = Some(e.id.clone());
-                    }
-                    if v4.is_supernet_of(candidate) {
-                        let mut x = e.clone();
-                        x.parent = Some(c.id.clone());
-                        self.replace(i, x);
-                    }
-                }
-                self.cidrs.push(c.to_owned());
-                Ok(c)
+        self.check_protocol(entry.cidr)?;
+
+        if self.contains(entry.cidr) || self.index.ids.contains_key(&entry.id) {
+            return Err(IpamError::DuplicateEntry(entry.cidr.to_string()));
+        }
+
+        // any children the new entry takes over are moved with `set_parent`,
+        // driven by their own CidrEntryReparented events
+        let mut c = entry;
+        c.parent = self.parent_of(c.cidr).map(|r| r.id);
+
+        self.cidrs.push(c.to_owned());
+        self.index_insert(self.cidrs.len() - 1);
+        Ok(c)
+    }
+
+    pub(crate) fn set_parent(&mut self, id: &CidrId, parent: Option<CidrId>) -> Result<(), IpamError> {
+        match self.index.ids.get(id) {
+            Some(&i) => {
+                self.cidrs[i].parent = parent;
+                Ok(())
             }
+            None => Err(IpamError::EntryNotFound(id.to_string())),
+        }
+    }
 
-            (IPProtocolFamily::V6, IpNetwork::V6(v6)) => {
-                let mut c = entry;
-                let cidrs = self.cidrs.clone();
-
-                for (i, e) in cidrs.iter().enumerate() {
-                    let candidate = match e.cidr {
-                        IpNetwork::V6(x) => Ok(x),
-                        _ => Err(IpamError::InvalidProtocol),
-                    }
-                    .expect("Dead code, can't get here");
-
-                    if v6.is_subnet_of(candidate) {
-                        c.parent = Some(e.id.clone());
-                    }
-                    if v6.is_supernet_of(candidate) {
-                        let mut x = e.clone();
-                        x.parent = Some(c.id.clone());
-                        self.replace(i, x);
-                    }
-                }
-                self.cidrs.push(c.to_owned());
-                Ok(c)
+    /// Take an entry out of the Ipam, leaving any children where they are.
+    /// A paired entry is refused, it has to be unpaired first.
+    pub(crate) fn remove_entry(&mut self, id: &CidrId) -> Result<CidrEntry, IpamError> {
+        let idx = *self.index.ids.get(id).ok_or_else(|| IpamError::EntryNotFound(id.to_string()))?;
+        if let Some(other) = &self.cidrs[idx].pair {
+            return Err(IpamError::AlreadyPaired(id.to_string(), other.to_string()))
+        }
+        let last = self.cidrs.len() - 1;
+
+        // the last entry is swapped into the gap, so it is re-indexed too
+        self.index_remove(idx);
+        if idx != last {
+            self.index_remove(last);
+        }
+        let removed = self.cidrs.swap_remove(idx);
+        if idx != last {
+            self.index_insert(idx);
+        }
+        Ok(removed)
+    }
+
+    /// Link a V4 entry and a V6 entry as the two halves of one dual-stack subnet
+    pub(crate) fn set_pair(&mut self, v4: &CidrId, v6: &CidrId) -> Result<(), IpamError> {
+        let a = *self.index.ids.get(v4).ok_or_else(|| IpamError::EntryNotFound(v4.to_string()))?;
+        let b = *self.index.ids.get(v6).ok_or_else(|| IpamError::EntryNotFound(v6.to_string()))?;
+        self.cidrs[a].pair = Some(v6.clone());
+        self.cidrs[b].pair = Some(v4.clone());
+        Ok(())
+    }
+
+    /// Drop the link between an entry and its pair, on both sides
+    pub(crate) fn clear_pair(&mut self, id: &CidrId) -> Result<(), IpamError> {
+        let i = *self.index.ids.get(id).ok_or_else(|| IpamError::EntryNotFound(id.to_string()))?;
+        if let Some(other) = self.cidrs[i].pair.take() {
+            if let Some(&j) = self.index.ids.get(&other) {
+                self.cidrs[j].pair = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// The entry paired with `entry`, if there is one
+    pub(crate) fn pair_of(&self, entry: &CidrEntry) -> Option<CidrEntry> {
+        entry.pair.as_ref().and_then(|p| self.find(p))
+    }
+
+    /// Give an entry a new CIDR, leaving everything else about it as it was
+    pub(crate) fn resize_entry(&mut self, id: &CidrId, cidr: IpNetwork) -> Result<(), IpamError> {
+        let idx = *self.index.ids.get(id).ok_or_else(|| IpamError::EntryNotFound(id.to_string()))?;
+        let mut resized = self.cidrs[idx].clone();
+        resized.cidr = cidr;
+        self.replace(idx, resized);
+        Ok(())
+    }
+
+    /// Whether `entry` can become `to` where it stands: still inside its parent,
+    /// clear of everything but its own descendants, and around all its children
+    pub(crate) fn check_resize(&self, entry: &CidrEntry, to: IpNetwork) -> Result<(), IpamError> {
+        let old = prefix_key(&entry.cidr);
+        let new = prefix_key(&to);
+
+        if let Some(parent) = entry.parent.as_ref().and_then(|p| self.find(p)) {
+            let outer = prefix_key(&parent.cidr);
+            if outer.len >= new.len || !outer.contains(&new) {
+                return Err(IpamError::BadRequest(format!("{} does not fit inside {}", to, parent.cidr)))
+            }
+        }
+        for child in self.children_of(entry.cidr) {
+            if !new.contains(&occupied_key(&child.cidr)) {
+                return Err(IpamError::BadRequest(format!("{} would fall outside {}", child.cidr, to)))
             }
         }
+        let collision = self.overlapping(to).into_iter().find(|ce| {
+            let k = occupied_key(&ce.cidr);
+            ce.id != entry.id && !old.contains(&k) && !(k.contains(&new) && k.len < new.len)
+        });
+        match collision {
+            Some(ce) if ce.cidr == to => Err(IpamError::DuplicateEntry(to.to_string())),
+            Some(ce) => Err(IpamError::Conflict(to.to_string(), format!("{} ({})", ce.id, ce.cidr))),
+            None => Ok(()),
+        }
+    }
+
+    /// Move an entry to a new lifecycle state
+    pub(crate) fn set_state(&mut self, id: &CidrId, state: EntryState) -> Result<(), IpamError> {
+        match self.index.ids.get(id) {
+            Some(&i) => {
+                self.cidrs[i].state = state;
+                Ok(())
+            }
+            None => Err(IpamError::EntryNotFound(id.to_string())),
+        }
+    }
+
+    /// Hold a released block back from allocation, dropping any quarantine
+    /// already over by the time it was released
+    pub(crate) fn quarantine_block(&mut self, block: Quarantined, released_at: u64) {
+        self.quarantine.retain(|q| q.until > released_at);
+        self.quarantine.push(block);
+    }
+
+    /// The blocks still in quarantine at `now`, in seconds since the epoch
+    pub fn quarantined(&self, now: u64) -> Vec<&Quarantined> {
+        self.quarantine.iter().filter(|q| q.until > now).collect()
+    }
+
+    /// The labels of an entry, to change in place
+    pub(crate) fn attributes_mut(&mut self, id: &CidrId) -> Result<&mut Attributes, IpamError> {
+        match self.index.ids.get(id) {
+            Some(&i) => Ok(&mut self.cidrs[i].attributes),
+            None => Err(IpamError::EntryNotFound(id.to_string())),
+        }
     }
 
     pub(crate) fn replace(&mut self, idx: usize, new_entry: CidrEntry) -> CidrEntry {
-        mem::replace(&mut self.cidrs[idx], new_entry)
+        self.index_remove(idx);
+        let old = mem::replace(&mut self.cidrs[idx], new_entry);
+        self.index_insert(idx);
+        old
     }
 
     pub(crate) fn size(&self) -> usize {
@@ -257,20 +629,309 @@ impl Ipam {
     }
 
     pub(crate) fn contains(&self, search: IpNetwork) -> bool {
-        self.cidrs.iter().any(|ce| ce.cidr == search)
+        self.position_of(&search).is_some()
+    }
+
+
+    /// Whether attributes for an entry at `cidr` fit the Ipam's schema, when it has one
+    pub(crate) fn check_schema(&self, cidr: IpNetwork, attributes: &Attributes) -> Result<(), IpamError> {
+        let violations = self.schema.as_ref().map(|s| s.check(&cidr, attributes)).unwrap_or_default();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(IpamError::SchemaViolation(cidr.to_string(), violations))
+        }
     }
 
+    /// Every entry breaking the current schema, in address order, with what is wrong with each
+    pub(crate) fn schema_violations(&self) -> Vec<(&CidrEntry, Vec<Violation>)> {
+        let schema = match &self.schema {
+            Some(schema) => schema,
+            None => return vec![],
+        };
+        let mut found: Vec<(&CidrEntry, Vec<Violation>)> = self.cidrs.iter()
+            .map(|ce| (ce, schema.check(&ce.cidr, &ce.attributes)))
+            .filter(|(_, violations)| !violations.is_empty())
+            .collect();
+        found.sort_by_key(|(ce, _)| ce.address_order());
+        found
+    }
+
+    /// Every entry with host bits set, along with the network it would normalise to
+    pub(crate) fn non_canonical(&self) -> Vec<(&CidrEntry, IpNetwork)> {
+        let mut found: Vec<(&CidrEntry, IpNetwork)> = self.cidrs.iter()
+            .filter(|ce| !ce.is_canonical())
+            .map(|ce| (ce, IpNetwork::new(ce.cidr.network(), ce.cidr.prefix()).unwrap()))
+            .collect();
+        found.sort_by_key(|(ce, _)| ce.address_order());
+        found
+    }
 
     pub(crate) fn missing_supernets(&self) -> Vec<IpNetwork> {
         let mut results = vec![];
         for e in self.cidrs.iter() {
             let p = IpNetwork::new(e.cidr.network(), e.cidr.prefix()).unwrap();
-            if !self.contains(p) {
+            if !self.contains(p) && !results.contains(&p) {
                 results.push(p);
             }
         }
         results
     }
+
+    /// The minimal set of CIDRs covering the space inside `parent` not taken
+    /// up by any of its children, in address order.
+    pub(crate) fn free_blocks(&self, parent: IpNetwork) -> Vec<IpNetwork> {
+        self.free_blocks_around(parent, vec![])
+    }
+
+    /// The free blocks inside `parent` that can be handed out at `now`,
+    /// skipping any still in quarantine
+    pub(crate) fn available_blocks(&self, parent: IpNetwork, now: u64) -> Vec<IpNetwork> {
+        let held = self.quarantined(now)
+            .into_iter()
+            .filter(|q| q.cidr.is_ipv6() == parent.is_ipv6())
+            .map(|q| occupied_key(&q.cidr))
+            .collect();
+        self.free_blocks_around(parent, held)
+    }
+
+    /// As `free_blocks`, with the `held` space counted as used too
+    fn free_blocks_around(&self, parent: IpNetwork, held: Vec<PrefixKey>) -> Vec<IpNetwork> {
+        let parent_key = prefix_key(&parent);
+        let mut used: Vec<PrefixKey> = self.children_of(parent)
+            .iter()
+            .map(|c| occupied_key(&c.cidr))
+            .chain(held.into_iter().filter(|k| parent_key.contains(k) || k.contains(&parent_key)))
+            .collect();
+        used.sort();
+
+        let mut free = vec![];
+        let mut cursor = Some(parent_key.first());
+        for u in used {
+            let start = match cursor {
+                Some(c) => c,
+                None => break,
+            };
+            if u.first() > start {
+                free.extend(range_prefixes(start, u.first() - 1));
+            }
+            if u.last() >= start {
+                cursor = u.last().checked_add(1);
+            }
+        }
+        if let Some(start) = cursor {
+            if start <= parent_key.last() {
+                free.extend(range_prefixes(start, parent_key.last()));
+            }
+        }
+
+        free.into_iter().map(|k| network_of(k, &parent)).collect()
+    }
+
+    /// The free blocks inside `parent` whose prefix length lies between
+    /// `min_prefix_len` and `max_prefix_len`, either end being open when None
+    pub fn gaps(&self, parent: IpNetwork, min_prefix_len: Option<u8>, max_prefix_len: Option<u8>) -> Vec<IpNetwork> {
+        self.free_blocks(parent)
+            .into_iter()
+            .filter(|b| min_prefix_len.map_or(true, |min| b.prefix() >= min))
+            .filter(|b| max_prefix_len.map_or(true, |max| b.prefix() <= max))
+            .collect()
+    }
+
+    /// How much of `cidr` is taken up by the entries beneath it.
+    ///
+    /// IPv4 is counted in addresses. IPv6 is counted in blocks of the Ipam's
+    /// `v6_utilization_unit` (/64 by default), where a block is used as soon
+    /// as anything sits in it. An IPv6 entry smaller than that unit is counted
+    /// in addresses instead.
+    pub fn utilization(&self, cidr: IpNetwork) -> Utilization {
+        let unit = match cidr {
+            IpNetwork::V6(v6) => {
+                let unit = self.cfg.as_ref().map_or(DEFAULT_V6_UNIT, |c| c.v6_utilization_unit);
+                if v6.prefix() <= unit { unit } else { 128 }
+            },
+            IpNetwork::V4(_) => 32,
+        };
+
+        let total = units_in(cidr.prefix(), unit);
+        let free: u128 = self.free_blocks(cidr)
+            .iter()
+            .filter(|b| b.prefix() <= unit)
+            .map(|b| units_in(b.prefix(), unit))
+            .sum();
+        Utilization::new(Some(cidr), unit, total, total - free)
+    }
+
+    /// Utilization across the top level entries (those without a parent) of one protocol family.
+    ///
+    /// Top level IPv6 entries smaller than the unit are counted in addresses, so
+    /// when there are any the whole family is counted in addresses.
+    pub fn utilization_rollup(&self, v6: bool) -> Utilization {
+        let top_level: Vec<Utilization> = self.cidrs.iter()
+            .filter(|ce| ce.parent.is_none() && ce.is_canonical() && ce.cidr.is_ipv6() == v6)
+            .map(|ce| self.utilization(ce.cidr))
+            .collect();
+        let unit = top_level.iter().map(|u| u.unit).max()
+            .unwrap_or(if v6 { DEFAULT_V6_UNIT } else { 32 });
+
+        // each block of a coarser unit is this many of the finest
+        let scale = |u: &Utilization, n: u128| n.saturating_mul(1u128.checked_shl((unit - u.unit) as u32).unwrap_or(u128::MAX));
+        Utilization::new(
+            None,
+            unit,
+            top_level.iter().fold(0u128, |sum, u| sum.saturating_add(scale(u, u.total))),
+            top_level.iter().fold(0u128, |sum, u| sum.saturating_add(scale(u, u.used))))
+    }
+
+    /// The entries whose address space overlaps that of `cidr`, least specific first
+    pub(crate) fn overlapping(&self, cidr: IpNetwork) -> Vec<&CidrEntry> {
+        let occupied = occupied_key(&cidr);
+        let trie = self.trie(&cidr);
+        let mut positions: Vec<usize> = trie.ancestors(&occupied)
+            .into_iter()
+            .chain(trie.subtree(&occupied))
+            .flat_map(|(_, slot)| slot.iter().copied())
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+
+        let mut results: Vec<&CidrEntry> = positions.into_iter()
+            .map(|i| &self.cidrs[i])
+            .filter(|ce| {
+                let k = occupied_key(&ce.cidr);
+                k.contains(&occupied) || occupied.contains(&k)
+            })
+            .collect();
+        results.sort_by_key(|ce| occupied_key(&ce.cidr).len);
+        results
+    }
+
+    /// Whether `cidr` is of a family the Ipam holds, a Dual Ipam holds both
+    pub(crate) fn check_protocol(&self, cidr: IpNetwork) -> Result<(), IpamError> {
+        match (&self.protocol, cidr) {
+            (IPProtocolFamily::V4, IpNetwork::V6(_)) => Err(IpamError::InvalidProtocol),
+            (IPProtocolFamily::V6, IpNetwork::V4(_)) => Err(IpamError::InvalidProtocol),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `cidr` can join the Ipam under its conflict policy. The error
+    /// names the entry standing in the way.
+    pub(crate) fn check_conflicts(&self, cidr: IpNetwork) -> Result<(), IpamError> {
+        let policy = self.cfg.as_ref().map(|c| c.conflicts).unwrap_or_default();
+        let conflict = |ce: &CidrEntry| IpamError::Conflict(cidr.to_string(), format!("{} ({})", ce.id, ce.cidr));
+        let is_host = |c: &IpNetwork| c.prefix() == max_prefix(c) || c.ip() != c.network();
+
+        let overlapping = match policy {
+            ConflictPolicy::Hierarchical => return Ok(()),
+            _ => self.overlapping(cidr),
+        };
+        match policy {
+            ConflictPolicy::LeafOnly if is_host(&cidr) => {
+                // a host entry sits in exactly one pool, clear of every other host
+                if let Some(ce) = overlapping.iter().find(|ce| is_host(&ce.cidr)) {
+                    return Err(conflict(ce))
+                }
+                if overlapping.is_empty() {
+                    return Err(IpamError::NotInPool(cidr.to_string()))
+                }
+                Ok(())
+            },
+            _ => match overlapping.first() {
+                Some(ce) => Err(conflict(ce)),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Every network entry enclosing `cidr`, closest first
+    pub(crate) fn ancestors_of(&self, cidr: IpNetwork) -> Vec<CidrEntryResult> {
+        let mut results = vec![];
+        let mut cur = self.parent_of(cidr);
+        while let Some(p) = cur {
+            cur = self.parent_of(p.cidr);
+            results.push(p);
+        }
+        results
+    }
+
+    /// Locate a free block of `prefix_len` inside `parent`, passing over
+    /// released blocks still in quarantine
+    pub(crate) fn next_free(&self, parent: IpNetwork, prefix_len: u8, strategy: AllocationStrategy) -> Result<IpNetwork, IpamError> {
+        // a block the size of the parent would be the parent itself
+        if prefix_len <= parent.prefix() || prefix_len > max_prefix(&parent) {
+            return Err(IpamError::BadRequest(format!("/{} does not fit inside {}", prefix_len, parent)));
+        }
+
+        let candidates = self.available_blocks(parent, now_secs())
+            .into_iter()
+            .filter(|b| b.prefix() <= prefix_len);
+
+        let block = match strategy {
+            AllocationStrategy::FirstFit => candidates.map(|b| (b, false)).next(),
+            // the tightest block, on a tie the lowest address
+            AllocationStrategy::BestFit => candidates
+                .fold(None, |best: Option<IpNetwork>, b| match best {
+                    Some(x) if x.prefix() >= b.prefix() => Some(x),
+                    _ => Some(b),
+                })
+                .map(|b| (b, false)),
+            AllocationStrategy::LastFit => candidates.last().map(|b| (b, true)),
+        };
+
+        match block {
+            None => Err(IpamError::NoFreeSpace(format!("/{} in {}", prefix_len, parent))),
+            Some((b, from_top)) => {
+                let key = prefix_key(&b);
+                let start = if from_top { key.last() } else { key.first() };
+                Ok(network_of(PrefixKey::new(start, prefix_len), &parent))
+            }
+        }
+    }
+
+    /// Locate the lowest free host address inside `parent`.
+    ///
+    /// The IPv4 network and broadcast addresses, and the IPv6 subnet-router anycast
+    /// address, are never used; nor are the `reserve_first` and `reserve_last`
+    /// addresses set in the Ipam config, or any address still in quarantine.
+    pub(crate) fn next_free_address(&self, parent: IpNetwork) -> Result<IpAddr, IpamError> {
+        let width = max_prefix(&parent);
+        // a /32 or /128 is a single address, the parent itself
+        if parent.prefix() >= width {
+            return Err(IpamError::BadRequest(format!("{} has no addresses beneath it", parent)));
+        }
+        let shift = 128 - width as u32;
+        let (mut head, mut tail) = match parent {
+            // RFC-3021 point to point /31s, and /32s, have no network or broadcast address
+            IpNetwork::V4(v4) if v4.prefix() < 31 => (1, 1),
+            // RFC-6164 /127 links have no subnet-router anycast address
+            IpNetwork::V6(v6) if v6.prefix() < 127 => (1, 0),
+            _ => (0, 0),
+        };
+        if let Some(cfg) = self.cfg.as_ref() {
+            head += cfg.reserve_first as u128;
+            tail += cfg.reserve_last as u128;
+        }
+
+        // work in host addresses, rather than left-aligned prefix keys
+        let key = prefix_key(&parent);
+        let first = (key.first() >> shift).checked_add(head);
+        let last = (key.last() >> shift).checked_sub(tail);
+        let (first, last) = match (first, last) {
+            (Some(f), Some(l)) if f <= l => (f, l),
+            _ => return Err(IpamError::NoFreeSpace(format!("addresses in {}", parent))),
+        };
+
+        self.available_blocks(parent, now_secs())
+            .iter()
+            .map(prefix_key)
+            .find_map(|b| {
+                let candidate = (b.first() >> shift).max(first);
+                if candidate <= (b.last() >> shift).min(last) { Some(candidate) } else { None }
+            })
+            .map(|a| network_of(PrefixKey::new(a << shift, width), &parent).ip())
+            .ok_or_else(|| IpamError::NoFreeSpace(format!("addresses in {}", parent)))
+    }
 }
     
 impl Aggregate for Ipam {
@@ -294,7 +955,13 @@ pub struct CidrEntry {
     pub uuid: Uuid,
     pub sysref: Option<String>,
     pub parent: Option<CidrId>,
-    pub attributes: HashSet<Label>, // would like to support a nested set of attributes here ideally
+    pub attributes: Attributes,
+    /// In a dual-stack Ipam, the entry of the other family this one is paired
+    /// with; the V6 counterpart of a V4 subnet, or the reverse
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pair: Option<CidrId>,
+    #[serde(default)]
+    pub state: EntryState,
 }
 
 impl Default for CidrEntry {
@@ -315,7 +982,7 @@ impl CidrEntry {
         s: &str,  
         id: Option<String>,
         sysref: Option<String>,
-        attributes: HashSet<Label>    
+        attributes: Attributes
         ) -> Result<CidrEntry, IpamError> {
 
         let cidr: IpNetwork = s.parse()?;
@@ -331,14 +998,18 @@ impl CidrEntry {
         Ok(cidr_entry)
     }
 
+    /// Orders entries by address: V4 before V6, then by network, prefix length
+    /// and finally host bits
+    pub fn address_order(&self) -> (bool, PrefixKey, IpAddr) {
+        (self.cidr.is_ipv6(), prefix_key(&self.cidr), self.cidr.ip())
+    }
 
-}
+    /// true when no host bits are set, `10.2.0.0/21` rather than `10.2.2.1/21`
+    pub fn is_canonical(&self) -> bool {
+        self.cidr.ip() == self.cidr.network()
+    }
 
-// #[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug)]
-// pub enum AttributeEntry {
-//     Attr(Label),
-//     SetOfAttr(HashSet<AttributeEntry>),
-// }
+}
 
 impl From<IpNetwork> for CidrEntry {
     fn from(cidr: IpNetwork) -> CidrEntry {
@@ -350,7 +1021,9 @@ impl From<IpNetwork> for CidrEntry {
             uuid: theuuid,
             sysref: None,
             parent: None,
-            attributes: HashSet::default(),
+            attributes: Attributes::default(),
+            pair: None,
+            state: EntryState::default(),
         }
     }
 }
@@ -379,23 +1052,199 @@ impl From<IpAddr> for CidrEntry {
 
 /* --- Ipam and Related Data Model -----------------------------------------*/
 
+/// Where an entry is in its life, from first being planned to being released
+#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryState {
+    /// pencilled in, nothing is using it yet
+    Planned,
+    /// held for a project or team
+    Reserved,
+    /// in use
+    Active,
+    /// on its way out, nothing new is allocated beneath it
+    Deprecated,
+    /// gone from the Ipam, the space sits in quarantine before reuse
+    Released,
+}
+
+impl Default for EntryState {
+    fn default() -> Self {
+        Self::Active
+    }
+}
+
+impl EntryState {
+    /// Whether an entry in this state may move to `next`
+    pub fn can_become(self, next: EntryState) -> bool {
+        use EntryState::*;
+        matches!((self, next),
+            (Planned, Reserved) | (Planned, Active) | (Planned, Released)
+            | (Reserved, Planned) | (Reserved, Active) | (Reserved, Released)
+            | (Active, Deprecated)
+            | (Deprecated, Active) | (Deprecated, Released))
+    }
+}
+
+/// A released block, kept from allocation until `until` (seconds since the epoch)
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Quarantined {
+    pub id: CidrId,
+    pub cidr: IpNetwork,
+    pub until: u64,
+}
+
+/// How a free block is chosen when allocating the next CIDR
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllocationStrategy {
+    /// the lowest free block that fits
+    FirstFit,
+    /// the smallest free block that fits, to keep larger runs intact
+    BestFit,
+    /// the highest free block that fits, allocating down from the top
+    LastFit,
+}
+
+impl Default for AllocationStrategy {
+    fn default() -> Self {
+        Self::FirstFit
+    }
+}
+
+/// What happens to the children of an entry being released
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChildPolicy {
+    /// refuse to release an entry that has children
+    Refuse,
+    /// release the children (and theirs) along with the entry
+    Cascade,
+    /// move the children up to the released entry's parent
+    Reparent,
+}
+
+impl Default for ChildPolicy {
+    fn default() -> Self {
+        Self::Refuse
+    }
+}
+
+/// Which labels the entries made by a split or a merge take from those they came from
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttributeCarry {
+    /// every label, refusing a merge where the entries disagree on a key
+    All,
+    /// only the labels every entry holds, with the same value
+    Common,
+    /// none, the new entries start out bare
+    Drop,
+}
+
+impl Default for AttributeCarry {
+    fn default() -> Self {
+        Self::Common
+    }
+}
+
 /// Configuration settings of a given Ipam
 #[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct IpamConfig {
     /// When a host CIDR is added, 10.99.99.68/24, setting this field to true
     /// will also add 10.99.99.0/24 if it is missing
     pub add_missing_supernet: bool,
+    /// Addresses at the start of a subnet, after the network (or IPv6
+    /// subnet-router anycast) address, never handed out as the next address.
+    /// 1 keeps the typical gateway free.
+    pub reserve_first: u32,
+    /// Addresses at the end of a subnet, before the broadcast address,
+    /// never handed out as the next address
+    pub reserve_last: u32,
+    /// What to do with a CIDR added with host bits set, 10.2.2.1/21
+    pub canonical: CanonicalPolicy,
+    /// IPv6 utilization is counted in blocks of this prefix length, rather than addresses
+    pub v6_utilization_unit: u8,
+    /// Which overlapping entries the Ipam accepts
+    pub conflicts: ConflictPolicy,
+    /// Seconds a released block stays in quarantine before it is allocated again,
+    /// 0 frees it straight away
+    pub quarantine_secs: u64,
 }
 
 impl Default for IpamConfig {
     fn default() -> Self {
         IpamConfig {
             add_missing_supernet: false,
+            reserve_first: 0,
+            reserve_last: 0,
+            canonical: CanonicalPolicy::Allow,
+            v6_utilization_unit: DEFAULT_V6_UNIT,
+            conflicts: ConflictPolicy::Hierarchical,
+            quarantine_secs: 0,
         }
     }
 }
 
+const DEFAULT_V6_UNIT: u8 = 64;
+
+/// How strictly an Ipam holds to canonical CIDRs, those without host bits set
+#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CanonicalPolicy {
+    /// refuse 10.2.2.1/21
+    Reject,
+    /// store 10.2.2.1/21 as 10.2.0.0/21
+    Normalize,
+    /// store 10.2.2.1/21 as given
+    Allow,
+}
+
+impl Default for CanonicalPolicy {
+    fn default() -> Self {
+        Self::Allow
+    }
+}
+
+/// Which entries may overlap one another
+#[derive(Hash, Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// entries nest inside one another, 10.0.0.0/24 within 10.0.0.0/16
+    Hierarchical,
+    /// no entry overlaps any other
+    Flat,
+    /// non-overlapping pools, holding host entries (/32, /128 or with host bits set) only
+    LeafOnly,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        Self::Hierarchical
+    }
+}
+
+/// How much of a prefix (or of an Ipam, when `cidr` is None) is used.
+/// `total`, `used` and `free` are counted in /`unit` blocks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Utilization {
+    pub cidr: Option<IpNetwork>,
+    pub unit: u8,
+    pub total: u128,
+    pub used: u128,
+    pub free: u128,
+    pub percent_used: f64,
+}
+
+impl Utilization {
+    fn new(cidr: Option<IpNetwork>, unit: u8, total: u128, used: u128) -> Self {
+        let percent_used = if total == 0 { 0.0 } else { used as f64 * 100.0 / total as f64 };
+        Utilization { cidr, unit, total, used, free: total - used, percent_used }
+    }
+}
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct CidrEntryResult{
     pub cidr: IpNetwork,
     pub id: CidrId,
@@ -408,6 +1257,12 @@ mod tests {
 
     use super::*;
     use crate::common;
+    use crate::commands::{AddCidrEntry, AddMissingSupernets, ReleaseCidrEntry, EntryRef, AddAttributeToCidr,
+        RemoveAttributeFromCidr, RemoveAttributeByKeyFromCidr, ReplaceAttributeOnCidr, PairCidrEntries, UnpairCidrEntry,
+        SetAttributeSchema, ChangeCidrEntryState, AllocateNextCidr, SplitCidrEntry, MergeCidrEntries,
+        ResizeCidrEntry};
+    use crate::events::{CidrEntryReleased, IpamEvent};
+    use cqrs_es::{AggregateError, Command, DomainEvent};
     use rand::Rng;
 
     fn get_net4_address() -> ipnetwork::Ipv4Network {
@@ -464,6 +1319,837 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_parent_is_closest_enclosing_entry() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        for c in ["10.0.0.0/8", "10.20.0.0/16", "10.20.4.0/24"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+        let slash16 = ipam.find(&IpNetwork::try_from("10.20.0.0/16").unwrap()).unwrap();
+        let slash24 = ipam.find(&IpNetwork::try_from("10.20.4.0/24").unwrap()).unwrap();
+        assert_eq!(slash24.parent, Some(slash16.id.clone()));
+
+        let host = ipam.add_entry(CidrEntry::try_from("10.20.4.7/32").unwrap()).unwrap();
+        assert_eq!(host.parent, Some(slash24.id));
+
+        // host bits set, so it hangs beneath the /16 network entry itself
+        let loose = ipam.add_entry(CidrEntry::try_from("10.20.9.1/16").unwrap()).unwrap();
+        assert_eq!(loose.parent, Some(slash16.id));
+    }
+
+    /// Run a command against the ipam, applying the events it raises
+    fn execute<C: Command<Ipam, IpamEvent>>(ipam: &mut Ipam, command: C) -> Vec<IpamEvent> {
+        let events = command.handle(ipam).expect("command failed");
+        for e in events.iter() {
+            e.clone().apply(ipam);
+        }
+        events
+    }
+
+    fn add(ipam: &mut Ipam, cidr: &str) -> CidrEntry {
+        let net = IpNetwork::try_from(cidr).unwrap();
+        execute(ipam, AddCidrEntry { cidr: s!(cidr), ..Default::default() });
+        ipam.find(&net).unwrap()
+    }
+
+    #[test]
+    fn test_children_of_returns_direct_children() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        for c in ["10.0.0.0/8", "10.1.0.0/16", "10.1.1.0/24", "10.2.0.0/24", "10.3.3.3/8"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+
+        let children: Vec<String> = ipam.children_of(IpNetwork::try_from("10.0.0.0/8").unwrap())
+            .iter().map(|c| c.cidr.to_string()).collect();
+        assert_eq!(children, vec!["10.3.3.3/8", "10.1.0.0/16", "10.2.0.0/24"]);
+
+        // works for a network not (yet) held
+        let children: Vec<String> = ipam.children_of(IpNetwork::try_from("10.0.0.0/14").unwrap())
+            .iter().map(|c| c.cidr.to_string()).collect();
+        assert_eq!(children, vec!["10.1.0.0/16", "10.2.0.0/24"]);
+
+        assert!(ipam.children_of(IpNetwork::try_from("10.3.3.3/8").unwrap()).is_empty());
+        assert!(ipam.children_of(IpNetwork::try_from("10.1.1.0/24").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_supernet_added_later_adopts_entries() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/8");
+        let a = add(&mut ipam, "10.1.1.0/24");
+        let b = add(&mut ipam, "10.1.2.0/24");
+        let deep = add(&mut ipam, "10.1.2.128/25");
+        let other = add(&mut ipam, "10.2.0.0/24");
+
+        let events = execute(&mut ipam, AddCidrEntry { cidr: s!("10.1.0.0/16"), ..Default::default() });
+        let mid = ipam.find(&IpNetwork::try_from("10.1.0.0/16").unwrap()).unwrap();
+
+        // only the direct children are re-parented, each with its own event
+        let reparented: Vec<CidrId> = events.iter().filter_map(|e| match e {
+            IpamEvent::CidrEntryReparented(r) => Some(r.id.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(reparented, vec![a.id.clone(), b.id.clone()]);
+
+        assert_eq!(mid.parent, Some(top.id.clone()));
+        assert_eq!(ipam.find(&a.id).unwrap().parent, Some(mid.id.clone()));
+        assert_eq!(ipam.find(&b.id).unwrap().parent, Some(mid.id.clone()));
+        assert_eq!(ipam.find(&deep.id).unwrap().parent, Some(b.id));
+        assert_eq!(ipam.find(&other.id).unwrap().parent, Some(top.id));
+    }
+
+    #[test]
+    fn test_duplicate_entries_are_rejected() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        ipam.add_entry(CidrEntry::try_from("10.0.0.0/8").unwrap()).unwrap();
+        assert!(ipam.add_entry(CidrEntry::try_from("10.0.0.0/8").unwrap()).is_err());
+        assert!(ipam.add_entry(CidrEntry::try_from("2001:db8::/32").unwrap()).is_err());
+        assert_eq!(ipam.size(), 1);
+    }
+
+    #[test]
+    fn test_reused_id_is_refused_before_any_event() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/8");
+        add(&mut ipam, "10.1.1.0/24");
+
+        // 10.1.0.0/16 would otherwise adopt 10.1.1.0/24 under the existing /8's id
+        let again = AddCidrEntry { cidr: s!("10.1.0.0/16"), id: Some(top.id.to_string()), ..Default::default() };
+        assert!(again.handle(&ipam).is_err());
+        let allocate = AllocateNextCidr { parent_id: Some(top.id.to_string()), prefix_len: 16, id: Some(top.id.to_string()), ..Default::default() };
+        assert!(allocate.handle(&ipam).is_err());
+        assert_eq!(ipam.size(), 2);
+    }
+
+    #[test]
+    fn test_serialized_shape_is_unchanged() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        ipam.add_entry(CidrEntry::try_from("10.0.0.0/8").unwrap()).unwrap();
+        ipam.add_entry(CidrEntry::try_from("10.1.0.0/16").unwrap()).unwrap();
+
+        let json = serde_json::to_value(&ipam).unwrap();
+        let mut keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["cfg", "cidrs", "id", "protocol", "uuid"]);
+
+        // the index is rebuilt when loading
+        let loaded: Ipam = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded, ipam);
+        assert!(loaded.contains(IpNetwork::try_from("10.1.0.0/16").unwrap()));
+    }
+
+    fn networks(found: Vec<IpNetwork>) -> Vec<String> {
+        found.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_free_blocks() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        for c in ["10.0.0.0/16", "10.0.0.0/24", "10.0.4.0/22", "10.0.4.9/24"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+
+        let free = ipam.free_blocks(IpNetwork::try_from("10.0.0.0/16").unwrap());
+        assert_eq!(networks(free), vec![
+            "10.0.1.0/24", "10.0.2.0/23", "10.0.8.0/21", "10.0.16.0/20",
+            "10.0.32.0/19", "10.0.64.0/18", "10.0.128.0/17"]);
+
+        // a host entry only takes its own address
+        let free = ipam.free_blocks(IpNetwork::try_from("10.0.4.0/24").unwrap());
+        assert_eq!(networks(free), vec![
+            "10.0.4.0/29", "10.0.4.8/32", "10.0.4.10/31", "10.0.4.12/30",
+            "10.0.4.16/28", "10.0.4.32/27", "10.0.4.64/26", "10.0.4.128/25"]);
+
+        let free = ipam.free_blocks(IpNetwork::try_from("10.0.0.0/24").unwrap());
+        assert_eq!(networks(free), vec!["10.0.0.0/24"]);
+    }
+
+    #[test]
+    fn test_gaps_by_prefix_len() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        for c in ["10.0.0.0/16", "10.0.0.0/24", "10.0.4.0/22"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+        let parent = IpNetwork::try_from("10.0.0.0/16").unwrap();
+
+        assert_eq!(ipam.gaps(parent, None, None), ipam.free_blocks(parent));
+        assert_eq!(networks(ipam.gaps(parent, Some(20), Some(23))), vec!["10.0.2.0/23", "10.0.8.0/21", "10.0.16.0/20"]);
+        assert_eq!(networks(ipam.gaps(parent, None, Some(18))), vec!["10.0.64.0/18", "10.0.128.0/17"]);
+        assert!(ipam.gaps(parent, Some(25), None).is_empty());
+    }
+
+    #[test]
+    fn test_next_free_strategies() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        for c in ["10.20.0.0/16", "10.20.0.0/26", "10.20.0.128/26",
+                  "10.20.1.0/24", "10.20.1.0/25", "10.20.1.128/25"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+        let parent = IpNetwork::try_from("10.20.0.0/16").unwrap();
+
+        let first = ipam.next_free(parent, 26, AllocationStrategy::FirstFit).unwrap();
+        assert_eq!(first.to_string(), "10.20.0.64/26");
+
+        let best = ipam.next_free(parent, 25, AllocationStrategy::BestFit).unwrap();
+        assert_eq!(best.to_string(), "10.20.2.0/25");
+
+        let last = ipam.next_free(parent, 24, AllocationStrategy::LastFit).unwrap();
+        assert_eq!(last.to_string(), "10.20.255.0/24");
+
+        assert!(ipam.next_free(parent, 8, AllocationStrategy::FirstFit).is_err());
+        let empty = IpNetwork::try_from("10.30.0.0/16").unwrap();
+        ipam.add_entry(CidrEntry::from(empty)).unwrap();
+        assert!(matches!(ipam.next_free(empty, 16, AllocationStrategy::FirstFit), Err(IpamError::BadRequest(_))));
+        let full = IpNetwork::try_from("10.20.1.0/24").unwrap();
+        assert!(ipam.next_free(full, 25, AllocationStrategy::FirstFit).is_err());
+        assert!(ipam.next_free(full, 26, AllocationStrategy::FirstFit).is_err());
+    }
+
+    #[test]
+    fn test_next_free_address() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        ipam.cfg = Some(IpamConfig { reserve_first: 1, reserve_last: 1, ..Default::default() });
+        for c in ["10.4.8.0/29", "10.4.8.2/32", "10.4.8.3/29"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+        let subnet = IpNetwork::try_from("10.4.8.0/29").unwrap();
+
+        // .0 network, .1 reserved, .2 and .3 taken
+        let next = ipam.next_free_address(subnet).unwrap();
+        assert_eq!(next.to_string(), "10.4.8.4");
+
+        ipam.add_entry(CidrEntry::from(IpAddr::from([10, 4, 8, 4]))).unwrap();
+        ipam.add_entry(CidrEntry::from(IpAddr::from([10, 4, 8, 5]))).unwrap();
+        // .6 reserved, .7 broadcast
+        assert!(ipam.next_free_address(subnet).is_err());
+
+        let p2p = IpNetwork::try_from("10.9.9.0/31").unwrap();
+        ipam.cfg = None;
+        assert_eq!(ipam.next_free_address(p2p).unwrap().to_string(), "10.9.9.0");
+
+        let host = IpNetwork::try_from("10.9.9.9/32").unwrap();
+        ipam.add_entry(CidrEntry::from(host)).unwrap();
+        assert!(matches!(ipam.next_free_address(host), Err(IpamError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_next_free_address_v6() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V6);
+        ipam.add_entry(CidrEntry::try_from("2001:db8:1::/64").unwrap()).unwrap();
+        let subnet = IpNetwork::try_from("2001:db8:1::/64").unwrap();
+
+        // skips the subnet-router anycast address
+        assert_eq!(ipam.next_free_address(subnet).unwrap().to_string(), "2001:db8:1::1");
+        ipam.add_entry(CidrEntry::try_from("2001:db8:1::1/128").unwrap()).unwrap();
+        assert_eq!(ipam.next_free_address(subnet).unwrap().to_string(), "2001:db8:1::2");
+
+        let host = IpNetwork::try_from("2001:db8:1::1/128").unwrap();
+        assert!(matches!(ipam.next_free_address(host), Err(IpamError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_remove_entry_keeps_index_in_step() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let a = ipam.add_entry(CidrEntry::try_from("10.0.0.0/8").unwrap()).unwrap();
+        let b = ipam.add_entry(CidrEntry::try_from("10.1.0.0/16").unwrap()).unwrap();
+        let c = ipam.add_entry(CidrEntry::try_from("10.2.0.0/16").unwrap()).unwrap();
+
+        assert_eq!(ipam.remove_entry(&a.id).unwrap().cidr, a.cidr);
+        assert!(ipam.remove_entry(&a.id).is_err());
+        assert_eq!(ipam.size(), 2);
+        assert!(!ipam.contains(a.cidr));
+        assert_eq!(ipam.find(&b.id).unwrap().cidr, b.cidr);
+        assert_eq!(ipam.find(&c.cidr).unwrap().id, c.id);
+    }
+
+    fn release(id: &CidrId, children: ChildPolicy) -> ReleaseCidrEntry {
+        ReleaseCidrEntry { id: Some(id.to_string()), children: Some(children), ..Default::default() }
+    }
+
+    #[test]
+    fn test_release_refuses_when_children_exist() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/8");
+        let leaf = add(&mut ipam, "10.1.0.0/16");
+
+        assert!(release(&top.id, ChildPolicy::Refuse).handle(&ipam).is_err());
+
+        let events = execute(&mut ipam, release(&leaf.id, ChildPolicy::Refuse));
+        assert_eq!(events, vec![IpamEvent::CidrEntryReleased(CidrEntryReleased { cidr_entry: leaf.clone() })]);
+        assert!(!ipam.contains(leaf.cidr));
+    }
+
+    #[test]
+    fn test_release_cascades_to_descendants() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/8");
+        let mid = add(&mut ipam, "10.1.0.0/16");
+        add(&mut ipam, "10.1.1.0/24");
+        add(&mut ipam, "10.1.1.5/32");
+        add(&mut ipam, "10.2.0.0/16");
+
+        let events = execute(&mut ipam, release(&mid.id, ChildPolicy::Cascade));
+        assert_eq!(events.len(), 3);
+        assert_eq!(ipam.size(), 2);
+        assert!(ipam.contains(top.cidr));
+        assert!(ipam.contains(IpNetwork::try_from("10.2.0.0/16").unwrap()));
+    }
+
+    #[test]
+    fn test_release_moves_children_up() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/8");
+        let mid = add(&mut ipam, "10.1.0.0/16");
+        let a = add(&mut ipam, "10.1.1.0/24");
+        let b = add(&mut ipam, "10.1.2.0/24");
+
+        execute(&mut ipam, release(&mid.id, ChildPolicy::Reparent));
+        assert!(!ipam.contains(mid.cidr));
+        assert_eq!(ipam.find(&a.id).unwrap().parent, Some(top.id.clone()));
+        assert_eq!(ipam.find(&b.id).unwrap().parent, Some(top.id));
+    }
+
+    fn move_to(ipam: &Ipam, entry: &CidrEntry, state: EntryState) -> Result<Vec<IpamEvent>, AggregateError> {
+        ChangeCidrEntryState { entry: EntryRef::by_id(&entry.id), state }.handle(ipam)
+    }
+
+    #[test]
+    fn test_lifecycle_transitions() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        execute(&mut ipam, AddCidrEntry { cidr: s!("10.0.0.0/16"), state: Some(EntryState::Planned), ..Default::default() });
+        let entry = ipam.find(&IpNetwork::try_from("10.0.0.0/16").unwrap()).unwrap();
+        assert_eq!(entry.state, EntryState::Planned);
+        assert!(AddCidrEntry { cidr: s!("10.1.0.0/16"), state: Some(EntryState::Deprecated), ..Default::default() }.handle(&ipam).is_err());
+
+        for state in [EntryState::Reserved, EntryState::Active, EntryState::Deprecated].iter() {
+            let events = move_to(&ipam, &entry, *state).unwrap();
+            assert_eq!(events.len(), 1);
+            events.into_iter().for_each(|e| e.apply(&mut ipam));
+            assert_eq!(ipam.find(&entry.id).unwrap().state, *state);
+        }
+        // no going back to planned, and staying put changes nothing
+        assert!(move_to(&ipam, &entry, EntryState::Planned).is_err());
+        assert!(move_to(&ipam, &entry, EntryState::Deprecated).unwrap().is_empty());
+
+        // nothing new beneath a deprecated entry
+        let allocate = AllocateNextCidr { parent_id: Some(entry.id.to_string()), prefix_len: 24, ..Default::default() };
+        assert!(allocate.handle(&ipam).is_err());
+
+        let events = move_to(&ipam, &entry, EntryState::Released).unwrap();
+        events.into_iter().for_each(|e| e.apply(&mut ipam));
+        assert_eq!(ipam.size(), 0);
+    }
+
+    #[test]
+    fn test_quarantine_is_skipped_by_allocation() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        ipam.cfg = Some(IpamConfig { quarantine_secs: 3600, ..Default::default() });
+        let parent = add(&mut ipam, "10.0.0.0/16");
+        let first = add(&mut ipam, "10.0.0.0/24");
+        let host = add(&mut ipam, "10.0.1.1/32");
+
+        let events = execute(&mut ipam, release(&first.id, ChildPolicy::Refuse));
+        assert_eq!(events.len(), 2);
+        execute(&mut ipam, release(&host.id, ChildPolicy::Refuse));
+        assert_eq!(ipam.quarantined(now_secs()).len(), 2);
+
+        // 10.0.1.0/24 still holds the quarantined 10.0.1.1
+        let next = ipam.next_free(parent.cidr, 24, AllocationStrategy::FirstFit).unwrap();
+        assert_eq!(next.to_string(), "10.0.2.0/24");
+        let inside = IpNetwork::try_from("10.0.1.0/24").unwrap();
+        ipam.add_entry(CidrEntry::from(inside)).unwrap();
+        assert_eq!(ipam.next_free_address(inside).unwrap().to_string(), "10.0.1.2");
+
+        // once the quarantine is over the space is free again
+        assert!(ipam.quarantined(now_secs() + 3600).is_empty());
+        ipam.quarantine.iter_mut().for_each(|q| q.until = 0);
+        assert_eq!(ipam.next_free(parent.cidr, 24, AllocationStrategy::FirstFit).unwrap().to_string(), "10.0.0.0/24");
+        assert_eq!(ipam.next_free_address(inside).unwrap().to_string(), "10.0.1.1");
+    }
+
+    #[test]
+    fn test_split_entry() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/16");
+        let entry = add(&mut ipam, "10.0.0.0/22");
+        execute(&mut ipam, AddAttributeToCidr { entry: EntryRef::by_id(&entry.id), attribute: Label::new("env", "dev") });
+        let existing = add(&mut ipam, "10.0.2.0/24");
+        let host = add(&mut ipam, "10.0.1.9/32");
+
+        let split = |prefix_len| SplitCidrEntry { entry: EntryRef::by_id(&entry.id), prefix_len, ..Default::default() };
+        assert!(split(22).handle(&ipam).is_err());
+        assert!(split(25).handle(&ipam).is_err(), "10.0.2.0/24 does not fit inside a /25");
+
+        execute(&mut ipam, split(24));
+        assert!(!ipam.contains(entry.cidr));
+        let parts: Vec<CidrEntry> = ["10.0.0.0/24", "10.0.1.0/24", "10.0.3.0/24"].iter()
+            .map(|c| ipam.find(&IpNetwork::try_from(*c).unwrap()).unwrap())
+            .collect();
+        for part in parts.iter() {
+            assert_eq!(part.parent, Some(top.id.clone()));
+            assert_eq!(labels(part), vec!["env=dev"]);
+        }
+        // the existing /24 stands in for its block, and children follow their block
+        assert_eq!(ipam.find(&existing.id).unwrap().parent, Some(top.id.clone()));
+        assert_eq!(ipam.find(&host.id).unwrap().parent, Some(parts[1].id.clone()));
+        assert_eq!(ipam.size(), 6);
+    }
+
+    #[test]
+    fn test_merge_entries() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/16");
+        let a = add(&mut ipam, "10.0.4.0/24");
+        let b = add(&mut ipam, "10.0.5.0/24");
+        let c = add(&mut ipam, "10.0.6.0/24");
+        let host = add(&mut ipam, "10.0.5.7/32");
+        for (e, site) in [(&a, "syd"), (&b, "mel")].iter() {
+            execute(&mut ipam, AddAttributeToCidr { entry: EntryRef::by_id(&e.id), attribute: Label::new("env", "dev") });
+            execute(&mut ipam, AddAttributeToCidr { entry: EntryRef::by_id(&e.id), attribute: Label::new("site", site) });
+        }
+
+        let merge = |ids: Vec<&CidrEntry>, attributes| MergeCidrEntries {
+            ids: ids.iter().map(|e| e.id.to_string()).collect(),
+            attributes: Some(attributes),
+            ..Default::default()
+        };
+        // 10.0.5.0 to 10.0.6.255 is not a single prefix, and 4 and 6 do not touch
+        assert!(merge(vec![&b, &c], AttributeCarry::Common).handle(&ipam).is_err());
+        assert!(merge(vec![&a, &c], AttributeCarry::Common).handle(&ipam).is_err());
+        assert!(merge(vec![&a], AttributeCarry::Common).handle(&ipam).is_err());
+        assert!(merge(vec![&a, &b], AttributeCarry::All).handle(&ipam).is_err(), "the sites disagree");
+
+        execute(&mut ipam, merge(vec![&b, &a], AttributeCarry::Common));
+        let merged = ipam.find(&IpNetwork::try_from("10.0.4.0/23").unwrap()).unwrap();
+        assert_eq!(merged.parent, Some(top.id.clone()));
+        assert_eq!(labels(&merged), vec!["env=dev"]);
+        assert_eq!(ipam.find(&host.id).unwrap().parent, Some(merged.id));
+        assert!(!ipam.contains(a.cidr) && !ipam.contains(b.cidr));
+        assert_eq!(ipam.size(), 4);
+    }
+
+    #[test]
+    fn test_resize_entry() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.1.0.0/16");
+        let entry = add(&mut ipam, "10.1.0.0/24");
+        let child = add(&mut ipam, "10.1.0.200/32");
+        let sibling = add(&mut ipam, "10.1.2.0/24");
+        let resize = |e: &CidrEntry, prefix_len| ResizeCidrEntry { entry: EntryRef::by_id(&e.id), prefix_len };
+
+        let events = execute(&mut ipam, resize(&entry, 23));
+        assert_eq!(events.len(), 1);
+        let grown = ipam.find(&entry.id).unwrap();
+        assert_eq!(grown.cidr.to_string(), "10.1.0.0/23");
+        assert_eq!((grown.uuid, grown.parent), (entry.uuid, Some(top.id.clone())));
+        assert_eq!(ipam.find(&child.id).unwrap().parent, Some(entry.id.clone()));
+        assert!(ipam.contains(grown.cidr) && !ipam.contains(entry.cidr));
+
+        // into the sibling, past the parent, off the boundary, and around the child
+        assert!(resize(&entry, 22).handle(&ipam).is_err());
+        assert!(resize(&entry, 16).handle(&ipam).is_err());
+        assert!(resize(&sibling, 22).handle(&ipam).is_err());
+        assert!(resize(&entry, 25).handle(&ipam).is_err());
+
+        execute(&mut ipam, resize(&entry, 24));
+        assert_eq!(ipam.find(&entry.id).unwrap().cidr, entry.cidr);
+        assert_eq!(ipam.next_free(top.cidr, 24, AllocationStrategy::FirstFit).unwrap().to_string(), "10.1.1.0/24");
+    }
+
+    fn labels(entry: &CidrEntry) -> Vec<String> {
+        let mut found: Vec<String> = entry.attributes.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn test_attribute_changes() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        execute(&mut ipam, AddCidrEntry { cidr: s!("10.0.0.0/8"), sysref: Some(s!("vpc::1")), ..Default::default() });
+        let by_sysref = EntryRef { sysref: Some(s!("vpc::1")), ..Default::default() };
+
+        let events = execute(&mut ipam, AddAttributeToCidr { entry: by_sysref.clone(), attribute: Label::new("env", "dev") });
+        assert_eq!(events.len(), 1);
+        execute(&mut ipam, AddAttributeToCidr { entry: by_sysref.clone(), attribute: Label::new("site", "syd1") });
+        // adding the same label again changes nothing
+        let events = execute(&mut ipam, AddAttributeToCidr { entry: by_sysref.clone(), attribute: Label::new("env", "dev") });
+        assert!(events.is_empty());
+
+        let entry = ipam.find(SysRef("vpc::1")).unwrap();
+        assert_eq!(labels(&entry), vec!["env=dev", "site=syd1"]);
+
+        let by_uuid = EntryRef { uuid: Some(entry.uuid), ..Default::default() };
+        let events = execute(&mut ipam, ReplaceAttributeOnCidr { entry: by_uuid.clone(), attribute: Label::new("env", "prod") });
+        assert_eq!(events.len(), 2);
+        assert_eq!(labels(&ipam.find(&entry.id).unwrap()), vec!["env=prod", "site=syd1"]);
+
+        execute(&mut ipam, RemoveAttributeFromCidr { entry: by_uuid.clone(), attribute: Label::new("site", "syd1") });
+        assert_eq!(labels(&ipam.find(&entry.id).unwrap()), vec!["env=prod"]);
+
+        let by_cidr = EntryRef { cidr: Some(s!("10.0.0.0/8")), ..Default::default() };
+        execute(&mut ipam, RemoveAttributeByKeyFromCidr { entry: by_cidr.clone(), key: s!("env") });
+        assert!(ipam.find(&entry.id).unwrap().attributes.is_empty());
+
+        // nothing left to remove
+        assert!(RemoveAttributeByKeyFromCidr { entry: by_cidr, key: s!("env") }.handle(&ipam).is_err());
+    }
+
+    #[test]
+    fn test_attribute_keys_are_unique() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let entry = add(&mut ipam, "10.0.0.0/8");
+        let by_id = EntryRef::by_id(&entry.id);
+
+        let vlan: Label = serde_json::from_str(r#"{"key": "vlan", "value": 100}"#).unwrap();
+        execute(&mut ipam, AddAttributeToCidr { entry: by_id.clone(), attribute: vlan });
+        let second = AddAttributeToCidr { entry: by_id.clone(), attribute: Label::new("vlan", "200") }.handle(&ipam);
+        match second {
+            Err(AggregateError::UserError(e)) => assert_eq!(e.code.as_deref(), Some("AttributeExists")),
+            _ => panic!("a second vlan should be refused"),
+        }
+
+        let site: Label = serde_json::from_str(r#"{"key": "site", "value": {"region": "syd", "rack": 12}}"#).unwrap();
+        execute(&mut ipam, ReplaceAttributeOnCidr { entry: by_id.clone(), attribute: site });
+        assert_eq!(labels(&ipam.find(&entry.id).unwrap()), vec![r#"site={"rack":12,"region":"syd"}"#, "vlan=100"]);
+
+        let found = ipam.search("label:vlan>=100 label:site.rack=12").unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[test]
+    fn test_schema_is_enforced() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let legacy = add(&mut ipam, "10.9.0.0/16");
+        let schema: AttributeSchema = serde_json::from_str(r#"{"keys": {
+            "env":  {"required": true, "values": ["dev", "prod"]},
+            "vlan": {"type": "number", "prefix_lens": {"min": 24}}
+        }}"#).unwrap();
+        execute(&mut ipam, SetAttributeSchema { schema: Some(schema.clone()) });
+        assert_eq!(ipam.schema, Some(schema));
+
+        let with_env = |cidr: &str, env: &str| AddCidrEntry {
+            cidr: s!(cidr),
+            attributes: vec![Label::new("env", env)].into_iter().collect(),
+            ..Default::default()
+        };
+        let without_env = AddCidrEntry { cidr: s!("10.0.0.0/16"), ..Default::default() }.handle(&ipam);
+        match without_env {
+            Err(AggregateError::UserError(e)) => {
+                assert_eq!(e.code.as_deref(), Some("SchemaViolation"));
+                assert_eq!(e.params.unwrap().get("env").map(|r| r.as_str()), Some("is required"));
+            },
+            _ => panic!("env is required"),
+        }
+        assert!(with_env("10.0.0.0/16", "qa").handle(&ipam).is_err());
+        execute(&mut ipam, with_env("10.0.0.0/16", "dev"));
+        let entry = ipam.find(&IpNetwork::try_from("10.0.0.0/16").unwrap()).unwrap();
+
+        // vlan is only for /24 and longer, and env can't go
+        let by_id = EntryRef::by_id(&entry.id);
+        let vlan: Label = serde_json::from_str(r#"{"key": "vlan", "value": 7}"#).unwrap();
+        assert!(AddAttributeToCidr { entry: by_id.clone(), attribute: vlan }.handle(&ipam).is_err());
+        assert!(RemoveAttributeByKeyFromCidr { entry: by_id.clone(), key: s!("env") }.handle(&ipam).is_err());
+        assert!(ReplaceAttributeOnCidr { entry: by_id.clone(), attribute: Label::new("env", "prod") }.handle(&ipam).is_ok());
+
+        // entries from before the schema are reported, not removed
+        let violations = ipam.schema_violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].0.id, legacy.id);
+
+        // a schema that can't be checked is refused
+        let bad: AttributeSchema = serde_json::from_str(r#"{"keys": {"a": {"pattern": "("}}}"#).unwrap();
+        assert!(SetAttributeSchema { schema: Some(bad) }.handle(&ipam).is_err());
+    }
+
+    #[test]
+    fn test_effective_attributes() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/16");
+        let mid = add(&mut ipam, "10.0.1.0/24");
+        let leaf = add(&mut ipam, "10.0.1.0/28");
+        let label = |entry: &CidrEntry, key: &str, value: &str| AddAttributeToCidr { entry: EntryRef::by_id(&entry.id), attribute: Label::new(key, value) };
+        execute(&mut ipam, label(&top, "site", "syd1"));
+        execute(&mut ipam, label(&top, "vrf", "prod"));
+        execute(&mut ipam, label(&top, "owner", "netops"));
+        execute(&mut ipam, label(&mid, "vrf", "dev"));
+
+        let effective = ipam.effective_attributes(&ipam.find(&leaf.id).unwrap());
+        assert_eq!(effective.get("site").map(|v| v.to_string()), Some(s!("syd1")));
+        assert_eq!(effective.get("vrf").map(|v| v.to_string()), Some(s!("dev")));
+        assert!(ipam.find(&leaf.id).unwrap().attributes.is_empty());
+
+        // owner stays where it is put
+        let schema: AttributeSchema = serde_json::from_str(r#"{"keys": {"owner": {"inherit": false}}}"#).unwrap();
+        execute(&mut ipam, SetAttributeSchema { schema: Some(schema) });
+        let effective = ipam.effective_attributes(&ipam.find(&leaf.id).unwrap());
+        assert_eq!(effective.get("owner"), None);
+        assert_eq!(ipam.effective_attributes(&ipam.find(&top.id).unwrap()).get("owner").map(|v| v.to_string()), Some(s!("netops")));
+
+        let found: Vec<&CidrId> = ipam.search("effective:site=syd1 effective:vrf=dev").unwrap().iter().map(|ce| &ce.id).collect();
+        assert_eq!(found, vec![&mid.id, &leaf.id]);
+        assert!(ipam.search("label:site=syd1 prefixlen=28").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let top = add(&mut ipam, "10.0.0.0/8");
+        add(&mut ipam, "10.1.0.0/16");
+        add(&mut ipam, "10.1.1.0/24");
+        add(&mut ipam, "192.168.0.0/24");
+
+        let found: Vec<String> = ipam.search(&format!("parent:{} OR orphan:true", top.id)).unwrap()
+            .iter().map(|c| c.cidr.to_string()).collect();
+        assert_eq!(found, vec!["10.0.0.0/8", "10.1.0.0/16", "192.168.0.0/24"]);
+
+        assert_eq!(ipam.search("within:10.0.0.0/8 prefixlen>=16").unwrap().len(), 2);
+        assert!(ipam.search("within:").is_err());
+
+        // entries without a sysref no longer panic the plain searches
+        assert_eq!(ipam.filter("192.168").len(), 1);
+        assert!(ipam.find("10.1.1.0").is_some());
+    }
+
+    #[test]
+    fn test_add_missing_supernet_when_configured() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        let host = add(&mut ipam, "10.99.99.68/24");
+        assert_eq!(ipam.size(), 1);
+        assert_eq!(host.parent, None);
+
+        ipam.cfg = Some(IpamConfig { add_missing_supernet: true, ..Default::default() });
+        let events = execute(&mut ipam, AddCidrEntry { cidr: s!("10.20.30.40/16"), ..Default::default() });
+        assert_eq!(events.len(), 2);
+        let network = ipam.find(&IpNetwork::try_from("10.20.0.0/16").unwrap()).unwrap();
+        let host2 = ipam.find(&IpNetwork::try_from("10.20.30.40/16").unwrap()).unwrap();
+        assert_eq!(host2.parent, Some(network.id));
+
+        // a canonical entry never needs one
+        let events = execute(&mut ipam, AddCidrEntry { cidr: s!("10.30.0.0/16"), ..Default::default() });
+        assert_eq!(events.len(), 1);
+
+        // backfill whatever was added before the setting was turned on
+        assert_eq!(ipam.missing_supernets().len(), 1);
+        execute(&mut ipam, AddMissingSupernets {});
+        assert!(ipam.missing_supernets().is_empty());
+        let network = ipam.find(&IpNetwork::try_from("10.99.99.0/24").unwrap()).unwrap();
+        assert_eq!(ipam.find(&host.id).unwrap().parent, Some(network.id));
+    }
+
+    #[test]
+    fn test_canonical_policy() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        add(&mut ipam, "10.2.2.1/21");
+        assert_eq!(ipam.non_canonical().len(), 1);
+        assert_eq!(ipam.non_canonical()[0].1.to_string(), "10.2.0.0/21");
+
+        ipam.cfg = Some(IpamConfig { canonical: CanonicalPolicy::Reject, ..Default::default() });
+        let rejected = AddCidrEntry { cidr: s!("10.3.3.1/21"), ..Default::default() }.handle(&ipam);
+        match rejected {
+            Err(AggregateError::UserError(e)) => assert_eq!(e.code, Some(s!("NonCanonical"))),
+            _ => panic!("should have been rejected"),
+        }
+        execute(&mut ipam, AddCidrEntry { cidr: s!("10.3.0.0/21"), ..Default::default() });
+
+        ipam.cfg = Some(IpamConfig { canonical: CanonicalPolicy::Normalize, ..Default::default() });
+        execute(&mut ipam, AddCidrEntry { cidr: s!("10.4.4.1/21"), ..Default::default() });
+        assert!(ipam.contains(IpNetwork::try_from("10.4.0.0/21").unwrap()));
+        assert!(!ipam.contains(IpNetwork::try_from("10.4.4.1/21").unwrap()));
+    }
+
+    #[test]
+    fn test_add_refuses_the_other_family() {
+        let refused = |protocol: IPProtocolFamily, cidr: &str| {
+            let ipam = Ipam::new_with_protcol("My Ipam", protocol);
+            let added = AddCidrEntry { cidr: s!(cidr), ..Default::default() }.handle(&ipam);
+            match added {
+                Err(AggregateError::UserError(e)) => e.code.as_deref() == Some("InvalidProtocol"),
+                _ => false,
+            }
+        };
+        assert!(refused(IPProtocolFamily::V4, "2001:db8::/48"));
+        assert!(refused(IPProtocolFamily::V6, "10.0.0.0/16"));
+        assert!(!refused(IPProtocolFamily::Dual, "2001:db8::/48"));
+        assert!(!refused(IPProtocolFamily::Dual, "10.0.0.0/16"));
+    }
+
+    #[test]
+    fn test_dual_stack_keeps_both_families() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::Dual);
+        for c in ["10.0.0.0/16", "10.0.1.0/24", "2001:db8::/48", "2001:db8:0:1::/64"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+        let v4 = ipam.find(&IpNetwork::try_from("10.0.1.0/24").unwrap()).unwrap();
+        let v6 = ipam.find(&IpNetwork::try_from("2001:db8:0:1::/64").unwrap()).unwrap();
+        assert_eq!(ipam.parent_of(v4.cidr).unwrap().cidr.to_string(), "10.0.0.0/16");
+        assert_eq!(ipam.parent_of(v6.cidr).unwrap().cidr.to_string(), "2001:db8::/48");
+
+        execute(&mut ipam, PairCidrEntries { v4: EntryRef::by_id(&v4.id), v6: EntryRef::by_id(&v6.id) });
+        let paired = ipam.pair_of(&ipam.find(&v4.id).unwrap()).unwrap();
+        assert_eq!(paired.id, v6.id);
+        assert_eq!(ipam.pair_of(&paired).unwrap().id, v4.id);
+
+        // the pairing goes with either half
+        let v4_parent = ipam.find(&IpNetwork::try_from("10.0.0.0/16").unwrap()).unwrap();
+        assert!(PairCidrEntries { v4: EntryRef::by_id(&v4_parent.id), v6: EntryRef::by_id(&v6.id) }.handle(&ipam).is_err());
+        execute(&mut ipam, release(&v4.id, ChildPolicy::Refuse));
+        assert_eq!(ipam.find(&v6.id).unwrap().pair, None);
+    }
+
+    #[test]
+    fn test_taking_out_a_paired_entry_unpairs_it_first() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::Dual);
+        let v6 = add(&mut ipam, "2001:db8:0:1::/64");
+        let pair = |ipam: &mut Ipam, v4: &CidrEntry| {
+            execute(ipam, PairCidrEntries { v4: EntryRef::by_id(&v4.id), v6: EntryRef::by_id(&v6.id) });
+        };
+        let unpaired_first = |events: &[IpamEvent], v4: &CidrEntry| match events.first() {
+            Some(IpamEvent::CidrEntriesUnpaired(e)) => assert!(e.v4 == v4.id && e.v6 == v6.id),
+            _ => panic!("expected the pair to be dropped first"),
+        };
+
+        // the model itself will not drop a pair along the way
+        let v4 = add(&mut ipam, "10.0.0.0/23");
+        pair(&mut ipam, &v4);
+        assert!(matches!(ipam.clone().remove_entry(&v4.id), Err(IpamError::AlreadyPaired(_, _))));
+
+        let events = execute(&mut ipam, SplitCidrEntry { entry: EntryRef::by_id(&v4.id), prefix_len: 24, ..Default::default() });
+        unpaired_first(&events, &v4);
+        assert!(matches!(events[1], IpamEvent::CidrEntrySplit(_)));
+        assert_eq!(ipam.find(&v6.id).unwrap().pair, None);
+
+        let a = ipam.find(&IpNetwork::try_from("10.0.0.0/24").unwrap()).unwrap();
+        let b = ipam.find(&IpNetwork::try_from("10.0.1.0/24").unwrap()).unwrap();
+        pair(&mut ipam, &b);
+        let events = execute(&mut ipam, MergeCidrEntries { ids: vec![a.id.to_string(), b.id.to_string()], ..Default::default() });
+        unpaired_first(&events, &b);
+        assert!(matches!(events[1], IpamEvent::CidrEntriesMerged(_)));
+        assert_eq!(ipam.find(&v6.id).unwrap().pair, None);
+
+        let merged = ipam.find(&IpNetwork::try_from("10.0.0.0/23").unwrap()).unwrap();
+        pair(&mut ipam, &merged);
+        let events = execute(&mut ipam, release(&v6.id, ChildPolicy::Refuse));
+        unpaired_first(&events, &merged);
+        assert!(matches!(events[1], IpamEvent::CidrEntryReleased(_)));
+        assert_eq!(ipam.find(&merged.id).unwrap().pair, None);
+    }
+
+    #[test]
+    fn test_pairing_needs_one_of_each_family() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::Dual);
+        let a = add(&mut ipam, "10.0.1.0/24");
+        let b = add(&mut ipam, "10.0.2.0/24");
+        assert!(PairCidrEntries { v4: EntryRef::by_id(&a.id), v6: EntryRef::by_id(&b.id) }.handle(&ipam).is_err());
+
+        let c = add(&mut ipam, "2001:db8:0:1::/64");
+        let events = execute(&mut ipam, PairCidrEntries { v4: EntryRef::by_id(&a.id), v6: EntryRef::by_id(&c.id) });
+        assert_eq!(events.len(), 1);
+        let events = execute(&mut ipam, UnpairCidrEntry { entry: EntryRef::by_id(&c.id) });
+        assert_eq!(events.len(), 1);
+        assert_eq!(ipam.find(&a.id).unwrap().pair, None);
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        for c in ["10.0.0.0/16", "10.0.0.0/24", "10.0.0.4/30", "10.0.0.9/24", "10.1.0.0/16"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+        let found = |c: &str| -> Vec<String> {
+            ipam.overlapping(IpNetwork::try_from(c).unwrap()).iter().map(|ce| ce.cidr.to_string()).collect()
+        };
+
+        assert_eq!(found("10.0.0.5/32"), vec!["10.0.0.0/16", "10.0.0.0/24", "10.0.0.4/30"]);
+        assert_eq!(found("10.0.0.5/25"), vec!["10.0.0.0/16", "10.0.0.0/24", "10.0.0.4/30"]);
+        assert_eq!(found("10.0.0.8/29"), vec!["10.0.0.0/16", "10.0.0.0/24", "10.0.0.9/24"]);
+        assert_eq!(found("10.0.0.0/8"), vec!["10.0.0.0/16", "10.1.0.0/16", "10.0.0.0/24", "10.0.0.4/30", "10.0.0.9/24"]);
+        assert!(found("10.2.0.0/16").is_empty());
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let with_policy = |conflicts: ConflictPolicy| {
+            let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+            ipam.cfg = Some(IpamConfig { conflicts, ..Default::default() });
+            ipam
+        };
+        let add_cmd = |cidr: &str| AddCidrEntry { cidr: String::from(cidr), ..Default::default() };
+
+        let mut ipam = with_policy(ConflictPolicy::Hierarchical);
+        execute(&mut ipam, add_cmd("10.0.0.0/16"));
+        assert!(add_cmd("10.0.0.0/24").handle(&ipam).is_ok());
+
+        let mut ipam = with_policy(ConflictPolicy::Flat);
+        execute(&mut ipam, add_cmd("10.0.0.0/16"));
+        let entry = ipam.find(&IpNetwork::try_from("10.0.0.0/16").unwrap()).unwrap();
+        match add_cmd("10.0.1.0/24").handle(&ipam) {
+            Err(AggregateError::UserError(e)) => {
+                assert_eq!(e.code.as_deref(), Some("Conflict"));
+                assert!(e.message.unwrap().contains(entry.id.as_str()));
+            },
+            _ => panic!("expected the /16 to conflict"),
+        }
+        assert!(add_cmd("10.0.0.0/8").handle(&ipam).is_err());
+        assert!(add_cmd("10.1.0.0/16").handle(&ipam).is_ok());
+
+        let mut ipam = with_policy(ConflictPolicy::LeafOnly);
+        execute(&mut ipam, add_cmd("10.0.0.0/24"));
+        execute(&mut ipam, add_cmd("10.0.0.5/32"));
+        assert_eq!(ipam.size(), 2);
+        let code = |r: Result<Vec<IpamEvent>, AggregateError>| match r {
+            Err(AggregateError::UserError(e)) => e.code,
+            _ => None,
+        };
+        assert_eq!(code(add_cmd("10.0.0.0/25").handle(&ipam)).as_deref(), Some("Conflict"));
+        assert_eq!(code(add_cmd("10.0.0.5/24").handle(&ipam)).as_deref(), Some("Conflict"));
+        assert_eq!(code(add_cmd("10.9.0.5/32").handle(&ipam)).as_deref(), Some("NotInPool"));
+        assert!(add_cmd("10.0.0.6/24").handle(&ipam).is_ok());
+        assert!(add_cmd("10.0.1.0/24").handle(&ipam).is_ok());
+    }
+
+    #[test]
+    fn test_utilization_v4() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
+        for c in ["10.0.0.0/16", "10.0.0.0/24", "10.0.1.0/25", "10.0.1.5/32", "10.0.2.7/16", "10.1.0.0/24"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+
+        let u = ipam.utilization(IpNetwork::try_from("10.0.0.0/16").unwrap());
+        assert_eq!((u.unit, u.total, u.used, u.free), (32, 65536, 256 + 128 + 1, 65536 - 385));
+
+        let u = ipam.utilization(IpNetwork::try_from("10.0.0.0/24").unwrap());
+        assert_eq!(u.used, 0);
+        assert_eq!(u.percent_used, 0.0);
+
+        // the two top level /16 and /24
+        let rollup = ipam.utilization_rollup(false);
+        assert_eq!((rollup.total, rollup.used), (65536 + 256, 385));
+    }
+
+    #[test]
+    fn test_utilization_v6_counts_units() {
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V6);
+        for c in ["2001:db8::/48", "2001:db8:0:1::/64", "2001:db8:0:2::5/128", "2001:db8:0:10::/60"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+
+        let u = ipam.utilization(IpNetwork::try_from("2001:db8::/48").unwrap());
+        assert_eq!((u.unit, u.total, u.used), (64, 65536, 1 + 1 + 16));
+        assert_eq!(u.percent_used, 18.0 * 100.0 / 65536.0);
+
+        // smaller than the unit, so counted in addresses
+        let u = ipam.utilization(IpNetwork::try_from("2001:db8:0:2::/120").unwrap());
+        assert_eq!((u.unit, u.total, u.used), (128, 256, 1));
+
+        // a top level /48 in /64s and a /120 in addresses, summed in addresses
+        let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V6);
+        for c in ["2001:db8::/48", "2001:db8:0:1::/64", "2001:db9::/120", "2001:db9::5/128"].iter() {
+            ipam.add_entry(CidrEntry::try_from(*c).unwrap()).unwrap();
+        }
+        let u = ipam.utilization_rollup(true);
+        assert_eq!((u.unit, u.total, u.used), (128, (65536 << 64) + 256, (1 << 64) + 1));
+    }
+
     fn test_find() {
         let mut ipam = Ipam::new_with_protcol("My Ipam", IPProtocolFamily::V4);
 
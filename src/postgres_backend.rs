@@ -0,0 +1,158 @@
+//! The Postgres backend, over the tables in `db/init.sql`.
+//!
+//! The `(aggregate_type, aggregate_id, sequence)` primary key on `events`
+//! is what refuses a commit that lost the race.
+//!
+//! Requests take turns on the one connection. A connection that has gone bad
+//! is replaced by a new one on the next request that needs it.
+
+use chrono::{TimeZone, Utc};
+use cqrs_es::AggregateError;
+use postgres::{Connection, TlsMode};
+use serde_json::Value;
+use std::sync::Mutex;
+
+use crate::backend::{concurrent_update, technical, Backend, StoredEvent};
+use crate::queries::PointInTime;
+
+const UNIQUE_VIOLATION: &str = "23505";
+
+pub struct PostgresBackend {
+    url: String,
+    /// None after a failed reconnect, until one succeeds
+    conn: Mutex<Option<Connection>>,
+}
+
+impl PostgresBackend {
+    pub fn connect(url: &str) -> Result<Self, AggregateError> {
+        let conn = Connection::connect(url, TlsMode::None).map_err(technical)?;
+        Ok(PostgresBackend { url: String::from(url), conn: Mutex::new(Some(conn)) })
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&Connection) -> Result<T, AggregateError>) -> Result<T, AggregateError> {
+        let mut held = self.conn.lock().map_err(technical)?;
+        let conn = match held.take() {
+            Some(conn) if !conn.is_desynchronized() => conn,
+            _ => Connection::connect(self.url.as_str(), TlsMode::None).map_err(technical)?,
+        };
+        let result = f(&conn);
+        *held = Some(conn);
+        result
+    }
+}
+
+/// An event's timestamp in milliseconds, as `StoredEvent` keeps it
+const MILLIS: &str = "(extract(epoch from timestamp) * 1000)::bigint";
+
+/// The columns `stored_event` reads, in its order
+const EVENT_COLUMNS: &str = "sequence, payload, metadata, (extract(epoch from timestamp) * 1000)::bigint";
+
+fn stored_event(row: postgres::rows::Row) -> Result<StoredEvent, AggregateError> {
+    let sequence: i64 = row.get(0);
+    let millis: i64 = row.get(3);
+    Ok(StoredEvent {
+        sequence: sequence as usize,
+        payload: row.get(1),
+        metadata: serde_json::from_value(row.get::<_, Value>(2)).map_err(technical)?,
+        timestamp: Utc.timestamp_millis(millis),
+    })
+}
+
+/// View names go into the SQL as the table name, so only plain identifiers will do
+fn table(name: &str) -> Result<&str, AggregateError> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(name)
+    } else {
+        Err(AggregateError::TechnicalError(format!("{} is not a view table", name)))
+    }
+}
+
+impl Backend for PostgresBackend {
+    fn events(&self, aggregate_type: &str, aggregate_id: &str, after: usize) -> Result<Vec<StoredEvent>, AggregateError> {
+        let sql = format!("SELECT {} FROM events \
+            WHERE aggregate_type = $1 AND aggregate_id = $2 AND sequence > $3 ORDER BY sequence", EVENT_COLUMNS);
+        let rows = self.with(|conn| conn.query(&sql, &[&aggregate_type, &aggregate_id, &(after as i64)]).map_err(technical))?;
+        rows.iter().map(stored_event).collect()
+    }
+
+    fn events_as_of(&self, aggregate_type: &str, aggregate_id: &str, point: &PointInTime) -> Result<Vec<StoredEvent>, AggregateError> {
+        // the first event past a time ends the read, found walking the primary key in sequence order
+        let (bound, until) = match point {
+            PointInTime::Sequence(sequence) => (String::from("sequence <= $3"), *sequence as i64),
+            PointInTime::At(time) => (format!("sequence < COALESCE((SELECT sequence FROM events \
+                WHERE aggregate_type = $1 AND aggregate_id = $2 AND {} > $3 ORDER BY sequence LIMIT 1), sequence + 1)", MILLIS),
+                time.timestamp_millis()),
+        };
+        let sql = format!("SELECT {} FROM events \
+            WHERE aggregate_type = $1 AND aggregate_id = $2 AND {} ORDER BY sequence", EVENT_COLUMNS, bound);
+        let rows = self.with(|conn| conn.query(&sql, &[&aggregate_type, &aggregate_id, &until]).map_err(technical))?;
+        rows.iter().map(stored_event).collect()
+    }
+
+    fn append(&self, aggregate_type: &str, aggregate_id: &str, current: usize, events: &[StoredEvent]) -> Result<(), AggregateError> {
+        self.with(|conn| {
+            let trans = conn.transaction().map_err(technical)?;
+            for event in events {
+                let metadata = serde_json::to_value(&event.metadata).map_err(technical)?;
+                let inserted = trans.execute(
+                    "INSERT INTO events (aggregate_type, aggregate_id, sequence, payload, metadata) VALUES ($1, $2, $3, $4, $5)",
+                    &[&aggregate_type, &aggregate_id, &(event.sequence as i64), &event.payload, &metadata]);
+                match inserted {
+                    Ok(_) => {},
+                    Err(e) if e.code().map(|c| c.code()) == Some(UNIQUE_VIOLATION) => return Err(concurrent_update(aggregate_id, current)),
+                    Err(e) => return Err(technical(e)),
+                }
+            }
+            trans.commit().map_err(technical)
+        })
+    }
+
+    fn aggregate_ids(&self, aggregate_type: &str) -> Result<Vec<String>, AggregateError> {
+        let rows = self.with(|conn| conn.query(
+            "SELECT DISTINCT aggregate_id FROM events WHERE aggregate_type = $1 ORDER BY aggregate_id",
+            &[&aggregate_type])
+            .map_err(technical))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn snapshot(&self, aggregate_type: &str, aggregate_id: &str) -> Result<Option<(usize, Value)>, AggregateError> {
+        let rows = self.with(|conn| conn.query(
+            "SELECT last_sequence, payload FROM snapshots WHERE aggregate_type = $1 AND aggregate_id = $2",
+            &[&aggregate_type, &aggregate_id])
+            .map_err(technical))?;
+        Ok(rows.iter().next().map(|row| (row.get::<_, i64>(0) as usize, row.get(1))))
+    }
+
+    fn save_snapshot(&self, aggregate_type: &str, aggregate_id: &str, sequence: usize, payload: &Value) -> Result<(), AggregateError> {
+        self.with(|conn| conn.execute(
+            "INSERT INTO snapshots (aggregate_type, aggregate_id, last_sequence, payload) VALUES ($1, $2, $3, $4) \
+                ON CONFLICT (aggregate_type, aggregate_id) \
+                DO UPDATE SET last_sequence = EXCLUDED.last_sequence, payload = EXCLUDED.payload, timestamp = CURRENT_TIMESTAMP",
+            &[&aggregate_type, &aggregate_id, &(sequence as i64), payload])
+            .map_err(technical))?;
+        Ok(())
+    }
+
+    fn view(&self, name: &str, id: &str) -> Result<Option<(usize, Value)>, AggregateError> {
+        let sql = format!("SELECT version, payload FROM {} WHERE query_instance_id = $1", table(name)?);
+        let rows = self.with(|conn| conn.query(&sql, &[&id]).map_err(technical))?;
+        Ok(rows.iter().next().map(|row| (row.get::<_, i64>(0) as usize, row.get(1))))
+    }
+
+    fn save_view(&self, name: &str, id: &str, loaded: usize, version: usize, payload: &Value) -> Result<(), AggregateError> {
+        let updated = if loaded == 0 {
+            let sql = format!("INSERT INTO {} (query_instance_id, version, payload) VALUES ($1, $2, $3)", table(name)?);
+            self.with(|conn| match conn.execute(&sql, &[&id, &(version as i64), payload]) {
+                Err(e) if e.code().map(|c| c.code()) == Some(UNIQUE_VIOLATION) => Ok(0),
+                other => other.map_err(technical),
+            })?
+        } else {
+            let sql = format!("UPDATE {} SET version = $2, payload = $3 WHERE query_instance_id = $1 AND version = $4", table(name)?);
+            self.with(|conn| conn.execute(&sql, &[&id, &(version as i64), payload, &(loaded as i64)]).map_err(technical))?
+        };
+        if updated == 0 {
+            return Err(concurrent_update(id, loaded));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+//! A path-compressed binary (patricia) trie keyed on IP prefixes.
+//!
+//! Keys are held left-aligned in a `u128`, so the same structure serves
+//! both IPv4 (the top 32 bits) and IPv6 prefixes. `Ipam` keeps one trie per
+//! protocol family; lookups cost O(prefix-length) rather than a walk over
+//! every CidrEntry.
+//!
+//! ```text
+//!            (10.0.0.0/8)
+//!              /        \
+//!   (10.0.0.0/16)    (10.128.0.0/9)   <-- branch nodes may hold no value
+//!        |
+//!   (10.0.4.0/22)
+//! ```
+
+use std::cmp::min;
+
+/// A network prefix, with every bit past `len` cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PrefixKey {
+    pub bits: u128,
+    pub len: u8,
+}
+
+/// The netmask for a prefix length, left-aligned in a u128
+pub fn mask(len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        !0u128 << (128 - len as u32)
+    }
+}
+
+impl PrefixKey {
+    pub fn new(bits: u128, len: u8) -> Self {
+        PrefixKey {
+            bits: bits & mask(len),
+            len,
+        }
+    }
+
+    /// true when `other` is this prefix, or sits inside it
+    pub fn contains(&self, other: &PrefixKey) -> bool {
+        other.len >= self.len && other.bits & mask(self.len) == self.bits
+    }
+
+    /// The first address (left-aligned) covered by this prefix
+    pub fn first(&self) -> u128 {
+        self.bits
+    }
+
+    /// The last address (left-aligned) covered by this prefix
+    pub fn last(&self) -> u128 {
+        self.bits | !mask(self.len)
+    }
+
+    fn bit(&self, idx: u8) -> usize {
+        ((self.bits >> (127 - idx as u32)) & 1) as usize
+    }
+
+    /// Number of leading bits shared by both prefixes
+    fn common(&self, other: &PrefixKey) -> u8 {
+        let diff = (self.bits ^ other.bits).leading_zeros() as u8;
+        min(min(self.len, other.len), diff)
+    }
+}
+
+/// The minimal set of prefixes exactly covering the (left-aligned, inclusive)
+/// address range `first..=last`, in address order.
+pub fn range_prefixes(first: u128, last: u128) -> Vec<PrefixKey> {
+    let mut results = vec![];
+    let mut cur = first;
+    while cur <= last {
+        // the largest block aligned at `cur` that does not run past `last`
+        let mut len = 128 - cur.trailing_zeros() as u8;
+        while PrefixKey::new(cur, len).last() > last {
+            len += 1;
+        }
+        let block = PrefixKey::new(cur, len);
+        results.push(block);
+        match block.last().checked_add(1) {
+            Some(next) => cur = next,
+            None => break,
+        }
+    }
+    results
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Node<V> {
+    key: PrefixKey,
+    value: Option<V>,
+    children: [Option<Box<Node<V>>>; 2],
+}
+
+impl<V> Node<V> {
+    fn new(key: PrefixKey, value: Option<V>) -> Self {
+        Node {
+            key,
+            value,
+            children: [None, None],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixTrie<V> {
+    root: Option<Box<Node<V>>>,
+    len: usize,
+}
+
+impl<V> Default for PrefixTrie<V> {
+    fn default() -> Self {
+        PrefixTrie { root: None, len: 0 }
+    }
+}
+
+impl<V> PrefixTrie<V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert a value at the prefix, returning the value it replaced
+    pub fn insert(&mut self, key: PrefixKey, value: V) -> Option<V> {
+        let old = Self::insert_at(&mut self.root, key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    fn insert_at(link: &mut Option<Box<Node<V>>>, key: PrefixKey, value: V) -> Option<V> {
+        if link.is_none() {
+            *link = Some(Box::new(Node::new(key, Some(value))));
+            return None;
+        }
+
+        let node = link.as_mut().expect("checked above");
+        if node.key == key {
+            return node.value.replace(value);
+        }
+        if node.key.contains(&key) {
+            let b = key.bit(node.key.len);
+            return Self::insert_at(&mut node.children[b], key, value);
+        }
+
+        // the new key diverges from (or is an ancestor of) this node, so split here
+        let old = link.take().expect("checked above");
+        let common = old.key.common(&key);
+        let split = if common == key.len {
+            let mut n = Node::new(key, Some(value));
+            let b = old.key.bit(key.len);
+            n.children[b] = Some(old);
+            n
+        } else {
+            let mut n = Node::new(PrefixKey::new(key.bits, common), None);
+            let b = key.bit(common);
+            n.children[1 - b] = Some(old);
+            n.children[b] = Some(Box::new(Node::new(key, Some(value))));
+            n
+        };
+        *link = Some(Box::new(split));
+        None
+    }
+
+    /// Exact match lookup
+    pub fn get(&self, key: &PrefixKey) -> Option<&V> {
+        let mut cur = self.root.as_ref();
+        while let Some(node) = cur {
+            if node.key == *key {
+                return node.value.as_ref();
+            }
+            if !node.key.contains(key) {
+                return None;
+            }
+            cur = node.children[key.bit(node.key.len)].as_ref();
+        }
+        None
+    }
+
+    pub fn get_mut(&mut self, key: &PrefixKey) -> Option<&mut V> {
+        let mut cur = self.root.as_mut();
+        while let Some(node) = cur {
+            if node.key == *key {
+                return node.value.as_mut();
+            }
+            if !node.key.contains(key) {
+                return None;
+            }
+            cur = node.children[key.bit(node.key.len)].as_mut();
+        }
+        None
+    }
+
+    /// Remove the value held at the prefix, collapsing any branch nodes left behind
+    pub fn remove(&mut self, key: &PrefixKey) -> Option<V> {
+        let old = Self::remove_at(&mut self.root, key);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    fn remove_at(link: &mut Option<Box<Node<V>>>, key: &PrefixKey) -> Option<V> {
+        let node = link.as_mut()?;
+        let old = if node.key == *key {
+            node.value.take()
+        } else if node.key.contains(key) {
+            let b = key.bit(node.key.len);
+            Self::remove_at(&mut node.children[b], key)
+        } else {
+            None
+        };
+        if old.is_some() {
+            Self::compact(link);
+        }
+        old
+    }
+
+    fn compact(link: &mut Option<Box<Node<V>>>) {
+        let replacement = match link {
+            Some(node) if node.value.is_none() => match (&node.children[0], &node.children[1]) {
+                (None, None) => Some(None),
+                (Some(_), None) => Some(node.children[0].take()),
+                (None, Some(_)) => Some(node.children[1].take()),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(r) = replacement {
+            *link = r;
+        }
+    }
+
+    /// The most specific prefix holding a value that covers `key` (including `key` itself)
+    pub fn longest_match(&self, key: &PrefixKey) -> Option<(PrefixKey, &V)> {
+        self.ancestors(key).pop()
+    }
+
+    /// Every prefix holding a value that covers `key` (including `key` itself),
+    /// least specific first
+    pub fn ancestors(&self, key: &PrefixKey) -> Vec<(PrefixKey, &V)> {
+        let mut results = vec![];
+        let mut cur = self.root.as_ref();
+        while let Some(node) = cur {
+            if !node.key.contains(key) {
+                break;
+            }
+            if let Some(v) = node.value.as_ref() {
+                results.push((node.key, v));
+            }
+            if node.key.len == key.len {
+                break;
+            }
+            cur = node.children[key.bit(node.key.len)].as_ref();
+        }
+        results
+    }
+
+    /// Every prefix holding a value inside `key` (including `key` itself), in address order
+    pub fn subtree(&self, key: &PrefixKey) -> Vec<(PrefixKey, &V)> {
+        let mut results = vec![];
+        self.walk(key, |k, v| {
+            results.push((k, v));
+            true
+        });
+        results
+    }
+
+    /// All values in the trie, in address order
+    pub fn iter(&self) -> Vec<(PrefixKey, &V)> {
+        self.subtree(&PrefixKey::new(0, 0))
+    }
+
+    /// Visit, in address order, every prefix holding a value inside `key`
+    /// (including `key` itself). Returning false from `visit` skips everything
+    /// beneath that prefix.
+    pub fn walk<'a, F>(&'a self, key: &PrefixKey, mut visit: F)
+    where
+        F: FnMut(PrefixKey, &'a V) -> bool,
+    {
+        let mut cur = self.root.as_ref();
+        while let Some(node) = cur {
+            if key.contains(&node.key) {
+                Self::walk_node(node, &mut visit);
+                return;
+            }
+            if !node.key.contains(key) {
+                return;
+            }
+            cur = node.children[key.bit(node.key.len)].as_ref();
+        }
+    }
+
+    fn walk_node<'a, F>(node: &'a Node<V>, visit: &mut F)
+    where
+        F: FnMut(PrefixKey, &'a V) -> bool,
+    {
+        if let Some(v) = node.value.as_ref() {
+            if !visit(node.key, v) {
+                return;
+            }
+        }
+        for child in node.children.iter().flatten() {
+            Self::walk_node(child, visit);
+        }
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn v4(a: u8, b: u8, c: u8, d: u8, len: u8) -> PrefixKey {
+        let addr = u32::from_be_bytes([a, b, c, d]) as u128;
+        PrefixKey::new(addr << 96, len)
+    }
+
+    #[test]
+    fn test_insert_and_exact_match() {
+        let mut trie = PrefixTrie::default();
+        trie.insert(v4(10, 0, 0, 0, 8), "a");
+        trie.insert(v4(10, 1, 0, 0, 16), "b");
+        trie.insert(v4(10, 0, 0, 0, 16), "c");
+        trie.insert(v4(192, 168, 0, 0, 16), "d");
+
+        assert_eq!(trie.len(), 4);
+        assert_eq!(trie.get(&v4(10, 1, 0, 0, 16)), Some(&"b"));
+        assert_eq!(trie.get(&v4(10, 0, 0, 0, 16)), Some(&"c"));
+        assert_eq!(trie.get(&v4(10, 2, 0, 0, 16)), None);
+        // the split node between 10/16 and 10.1/16 holds no value
+        assert_eq!(trie.get(&v4(10, 0, 0, 0, 15)), None);
+        assert_eq!(trie.insert(v4(10, 0, 0, 0, 8), "e"), Some("a"));
+        assert_eq!(trie.len(), 4);
+    }
+
+    #[test]
+    fn test_longest_match_and_ancestors() {
+        let mut trie = PrefixTrie::default();
+        trie.insert(v4(10, 0, 0, 0, 8), 8);
+        trie.insert(v4(10, 20, 0, 0, 16), 16);
+        trie.insert(v4(10, 20, 4, 0, 24), 24);
+
+        let lpm = trie.longest_match(&v4(10, 20, 4, 9, 32)).unwrap();
+        assert_eq!(lpm, (v4(10, 20, 4, 0, 24), &24));
+
+        let lpm = trie.longest_match(&v4(10, 20, 5, 0, 24)).unwrap();
+        assert_eq!(lpm, (v4(10, 20, 0, 0, 16), &16));
+
+        let found: Vec<i32> = trie.ancestors(&v4(10, 20, 4, 0, 24)).iter().map(|(_, v)| **v).collect();
+        assert_eq!(found, vec![8, 16, 24]);
+
+        assert!(trie.longest_match(&v4(11, 0, 0, 0, 8)).is_none());
+    }
+
+    #[test]
+    fn test_subtree_in_address_order() {
+        let mut trie = PrefixTrie::default();
+        trie.insert(v4(10, 0, 8, 0, 24), 3);
+        trie.insert(v4(10, 0, 0, 0, 16), 1);
+        trie.insert(v4(10, 0, 0, 0, 24), 2);
+        trie.insert(v4(10, 1, 0, 0, 24), 4);
+
+        let found: Vec<i32> = trie.subtree(&v4(10, 0, 0, 0, 16)).iter().map(|(_, v)| **v).collect();
+        assert_eq!(found, vec![1, 2, 3]);
+
+        let found: Vec<i32> = trie.subtree(&v4(10, 0, 0, 0, 20)).iter().map(|(_, v)| **v).collect();
+        assert_eq!(found, vec![2, 3]);
+
+        let all: Vec<i32> = trie.iter().iter().map(|(_, v)| **v).collect();
+        assert_eq!(all, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_remove_collapses_branches() {
+        let mut trie = PrefixTrie::default();
+        trie.insert(v4(10, 0, 0, 0, 24), 1);
+        trie.insert(v4(10, 0, 1, 0, 24), 2);
+        trie.insert(v4(10, 0, 0, 0, 16), 3);
+
+        assert_eq!(trie.remove(&v4(10, 0, 0, 0, 16)), Some(3));
+        assert_eq!(trie.remove(&v4(10, 0, 0, 0, 16)), None);
+        assert_eq!(trie.remove(&v4(10, 0, 0, 0, 24)), Some(1));
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.get(&v4(10, 0, 1, 0, 24)), Some(&2));
+        assert_eq!(trie.remove(&v4(10, 0, 1, 0, 24)), Some(2));
+        assert!(trie.is_empty());
+        assert!(trie.root.is_none());
+    }
+
+    #[test]
+    fn test_range_prefixes() {
+        // 10.0.1.0 - 10.0.3.255
+        let found = range_prefixes(v4(10, 0, 1, 0, 32).first(), v4(10, 0, 3, 255, 32).last());
+        assert_eq!(found, vec![v4(10, 0, 1, 0, 24), v4(10, 0, 2, 0, 23)]);
+
+        let found = range_prefixes(0, u128::MAX);
+        assert_eq!(found, vec![PrefixKey::new(0, 0)]);
+
+        let found = range_prefixes(v4(10, 0, 0, 7, 32).first(), v4(10, 0, 0, 7, 32).last());
+        assert_eq!(found, vec![v4(10, 0, 0, 7, 32)]);
+    }
+
+    #[test]
+    fn test_full_length_keys() {
+        let mut trie = PrefixTrie::default();
+        trie.insert(PrefixKey::new(u128::MAX, 128), "host");
+        trie.insert(PrefixKey::new(0, 0), "default");
+        assert_eq!(trie.longest_match(&PrefixKey::new(u128::MAX, 128)).unwrap().1, &"host");
+        assert_eq!(trie.longest_match(&PrefixKey::new(1, 128)).unwrap().1, &"default");
+        assert_eq!(PrefixKey::new(0, 0).last(), u128::MAX);
+    }
+}
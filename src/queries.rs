@@ -1,8 +1,14 @@
-use cqrs_es::{EventEnvelope, Query, QueryProcessor};
+use chrono::{DateTime, Utc};
+use cqrs_es::{DomainEvent, EventEnvelope, Query, QueryProcessor};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
-use crate::ipam_model::Ipam;
+use crate::attributes::Attributes;
+use crate::error::IpamError;
+use crate::ipam_model::{CidrEntry, CidrId, IPProtocolFamily, Ipam, IpamConfig, Utilization};
+use crate::search::SearchExpr;
 use crate::events::IpamEvent;
 
 pub struct SimpleLoggingQueryProcessor {}
@@ -17,9 +23,78 @@ impl QueryProcessor<Ipam, IpamEvent> for SimpleLoggingQueryProcessor {
     }
 }
 
+/// A point in an Ipam's history to read it as it stood; after the events
+/// committed up to a time, or up to and including a sequence number
+#[derive(Debug, Clone, PartialEq)]
+pub enum PointInTime {
+    At(DateTime<Utc>),
+    Sequence(usize),
+}
+
+impl PointInTime {
+    /// From the `as_of` (RFC 3339) and `sequence` request parameters, None when neither is given
+    pub fn from_params(as_of: Option<&str>, sequence: Option<usize>) -> Result<Option<PointInTime>, IpamError> {
+        match (as_of, sequence) {
+            (Some(_), Some(_)) => Err(IpamError::BadRequest(String::from("give one of as_of or sequence, not both"))),
+            (Some(t), None) => PointInTime::at(t).map(Some),
+            (None, Some(s)) => Ok(Some(PointInTime::Sequence(s))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Either an event sequence number, `42`, or an RFC 3339 time
+    pub fn parse(s: &str) -> Result<PointInTime, IpamError> {
+        match s.parse::<usize>() {
+            Ok(sequence) => Ok(PointInTime::Sequence(sequence)),
+            Err(_) => PointInTime::at(s),
+        }
+    }
+
+    fn at(t: &str) -> Result<PointInTime, IpamError> {
+        DateTime::parse_from_rfc3339(t)
+            .map(|t| PointInTime::At(t.with_timezone(&Utc)))
+            .map_err(|e| IpamError::BadRequest(format!("{} is not an RFC 3339 time, {}", t, e)))
+    }
+}
+
+/// A view built afresh from an Ipam's events, as given in sequence order
+pub fn replay<V>(aggregate_id: &str, events: Vec<(usize, IpamEvent)>) -> V
+    where V: Query<Ipam, IpamEvent> + Default
+{
+    let mut view = V::default();
+    for (sequence, payload) in events {
+        let envelope = EventEnvelope::new_with_metadata(String::from(aggregate_id), sequence, String::from("Ipam"), payload, HashMap::new());
+        view.update(&envelope);
+    }
+    view
+}
+
+/// Holds on to the events committed by a single command, so the
+/// web handler can report back what was done (e.g. which CIDR was allocated)
+#[derive(Clone, Default)]
+pub struct CommittedEvents {
+    events: Arc<Mutex<Vec<IpamEvent>>>,
+}
+
+impl CommittedEvents {
+    pub fn take(&self) -> Vec<IpamEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+impl QueryProcessor<Ipam, IpamEvent> for CommittedEvents {
+    fn dispatch(&self, _aggregate_id: &str, events: &[EventEnvelope<Ipam, IpamEvent>]) {
+        self.events.lock().unwrap().extend(events.iter().map(|e| e.payload.clone()));
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct IpamSummaryView {
     uuid: Option<Uuid>,
+    id: String,
+    protocol: IPProtocolFamily,
+    cfg: Option<IpamConfig>,
     total_cidr_entries: u64,
 }
 
@@ -29,11 +104,33 @@ impl Query<Ipam, IpamEvent> for IpamSummaryView {
         match &event.payload {
             IpamEvent::IpamCreated(payload) => {
                 self.uuid = Some(payload.uuid.clone());
+                self.id = payload.id.clone();
+                self.protocol = payload.protocol.clone();
+                self.cfg = payload.cfg.clone();
                 println!(":: <Query<Ipam, IpamEvent> for IpamSummaryView> : IpamCreated {}", payload.uuid);
             },
             IpamEvent::CidrEntryAdded(p) => {
                 println!(":: <Query<Ipam, IpamEvent> for IpamSummaryView> : CidrEntryAdded {}",p.cidr_entry.id);                
                 self.total_cidr_entries = self.total_cidr_entries + 1;
+            },
+            IpamEvent::CidrEntryReparented(_) => {},
+            IpamEvent::CidrAttributeAdded(_) => {},
+            IpamEvent::CidrAttributeRemoved(_) => {},
+            IpamEvent::CidrEntriesPaired(_) => {},
+            IpamEvent::CidrEntriesUnpaired(_) => {},
+            IpamEvent::AttributeSchemaChanged(_) => {},
+            IpamEvent::CidrEntryStateChanged(_) => {},
+            IpamEvent::CidrBlockQuarantined(_) => {},
+            IpamEvent::CidrEntrySplit(p) => {
+                self.total_cidr_entries = (self.total_cidr_entries + p.parts.len() as u64).saturating_sub(1);
+            },
+            IpamEvent::CidrEntriesMerged(p) => {
+                self.total_cidr_entries = (self.total_cidr_entries + 1).saturating_sub(p.cidr_entries.len() as u64);
+            },
+            IpamEvent::CidrEntryResized(_) => {},
+            IpamEvent::CidrEntryReleased(p) => {
+                println!(":: <Query<Ipam, IpamEvent> for IpamSummaryView> : CidrEntryReleased {}",p.cidr_entry.id);
+                self.total_cidr_entries = self.total_cidr_entries.saturating_sub(1);
             }
         }
     }
@@ -43,8 +140,242 @@ impl Default for IpamSummaryView {
     fn default() -> Self {
         IpamSummaryView {
             uuid: None,
+            id: Default::default(),
+            protocol: Default::default(),
+            cfg: None,
             total_cidr_entries: Default::default(),
         }
     }
 }
 
+/// The current entries of an Ipam, kept up to date from its events so
+/// reads don't have to replay the aggregate.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IpamCidrsView {
+    pub ipam: Ipam,
+}
+
+impl Query<Ipam, IpamEvent> for IpamCidrsView {
+    fn update(&mut self, event: &EventEnvelope<Ipam, IpamEvent>) {
+        event.payload.clone().apply(&mut self.ipam);
+    }
+}
+
+/// Utilization of every entry and of the Ipam as a whole, worked out as
+/// events arrive rather than on every read. Only the entry an event touches
+/// and the entries above it are recalculated.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IpamUtilizationView {
+    ipam: Ipam,
+    pub entries: HashMap<CidrId, Utilization>,
+    pub v4: Option<Utilization>,
+    pub v6: Option<Utilization>,
+}
+
+impl IpamUtilizationView {
+    fn recalculate(&mut self, cidr: ipnetwork::IpNetwork) {
+        for parent in self.ipam.ancestors_of(cidr) {
+            let utilization = self.ipam.utilization(parent.cidr);
+            self.entries.insert(parent.id, utilization);
+        }
+        self.v4 = Some(self.ipam.utilization_rollup(false));
+        self.v6 = Some(self.ipam.utilization_rollup(true));
+    }
+}
+
+impl Query<Ipam, IpamEvent> for IpamUtilizationView {
+    fn update(&mut self, event: &EventEnvelope<Ipam, IpamEvent>) {
+        event.payload.clone().apply(&mut self.ipam);
+        match &event.payload {
+            IpamEvent::CidrEntryAdded(p) => {
+                let entry = &p.cidr_entry;
+                self.entries.insert(entry.id.clone(), self.ipam.utilization(entry.cidr));
+                self.recalculate(entry.cidr);
+            },
+            IpamEvent::CidrEntryReleased(p) => {
+                self.entries.remove(&p.cidr_entry.id);
+                self.recalculate(p.cidr_entry.cidr);
+            },
+            IpamEvent::CidrEntrySplit(p) => {
+                self.entries.remove(&p.cidr_entry.id);
+                for part in p.parts.iter() {
+                    self.entries.insert(part.id.clone(), self.ipam.utilization(part.cidr));
+                }
+                self.recalculate(p.cidr_entry.cidr);
+            },
+            IpamEvent::CidrEntriesMerged(p) => {
+                for entry in p.cidr_entries.iter() {
+                    self.entries.remove(&entry.id);
+                }
+                self.entries.insert(p.into.id.clone(), self.ipam.utilization(p.into.cidr));
+                self.recalculate(p.into.cidr);
+            },
+            IpamEvent::CidrEntryResized(p) => {
+                self.entries.insert(p.id.clone(), self.ipam.utilization(p.to));
+                self.recalculate(p.to);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// An entry as it is read back, along with the attributes it ends up with
+/// once those inherited from its ancestors are merged in
+#[derive(Debug, Serialize)]
+pub struct CidrEntryView<'a> {
+    #[serde(flatten)]
+    pub entry: &'a CidrEntry,
+    pub effective_attributes: Attributes,
+}
+
+impl IpamCidrsView {
+    pub fn read<'a>(&self, entry: &'a CidrEntry) -> CidrEntryView<'a> {
+        CidrEntryView { entry, effective_attributes: self.ipam.effective_attributes(entry) }
+    }
+}
+
+/// Ascending or descending address order
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        Self::Asc
+    }
+}
+
+impl IpamCidrsView {
+    /// The entries matching `filter` (the same substring match as `Ipam::filter`)
+    /// and the `query`, sorted by address
+    pub fn list(&self, filter: Option<&str>, query: Option<&SearchExpr>, order: SortOrder) -> Vec<&CidrEntry> {
+        let mut found = match query {
+            Some(q) => self.ipam.matching(q),
+            None => self.ipam.cidrs.iter().collect(),
+        };
+        if let Some(f) = filter {
+            let filtered = self.ipam.filter(f);
+            found.retain(|c| filtered.iter().any(|x| x.id == c.id));
+        }
+        found.sort_by_key(|c| c.address_order());
+        if order == SortOrder::Desc {
+            found.reverse();
+        }
+        found
+    }
+}
+
+/// One page of a longer listing
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub items: Vec<T>,
+}
+
+impl<T> Page<T> {
+    pub fn of(all: Vec<T>, offset: usize, limit: usize) -> Self {
+        Page {
+            total: all.len(),
+            offset,
+            limit,
+            items: all.into_iter().skip(offset).take(limit).collect(),
+        }
+    }
+
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> Page<U> {
+        Page {
+            total: self.total,
+            offset: self.offset,
+            limit: self.limit,
+            items: self.items.into_iter().map(f).collect(),
+        }
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::events::{CidrEntryAdded, CidrEntryReleased, IpamCreated};
+
+    fn envelope(sequence: usize, payload: IpamEvent) -> EventEnvelope<Ipam, IpamEvent> {
+        EventEnvelope::new_with_metadata(String::from("ipam_1"), sequence, String::from("Ipam"), payload, HashMap::new())
+    }
+
+    #[test]
+    fn test_cidrs_view_lists_by_address() {
+        let mut view = IpamCidrsView::default();
+        view.update(&envelope(1, IpamEvent::IpamCreated(IpamCreated {
+            uuid: Uuid::new_v4(), id: String::from("ipam_1"), protocol: IPProtocolFamily::V4, cfg: None })));
+        for (i, c) in ["10.9.0.0/16", "10.1.0.0/16", "10.1.0.0/24"].iter().enumerate() {
+            let mut cidr_entry = CidrEntry::from(c.parse::<ipnetwork::IpNetwork>().unwrap());
+            cidr_entry.sysref = if i == 0 { Some(String::from("vpc::9")) } else { None };
+            view.update(&envelope(i + 2, IpamEvent::CidrEntryAdded(CidrEntryAdded { cidr_entry })));
+        }
+
+        let listed: Vec<String> = view.list(None, None, SortOrder::Asc).iter().map(|c| c.cidr.to_string()).collect();
+        assert_eq!(listed, vec!["10.1.0.0/16", "10.1.0.0/24", "10.9.0.0/16"]);
+
+        let listed: Vec<String> = view.list(Some("vpc::"), None, SortOrder::Desc).iter().map(|c| c.cidr.to_string()).collect();
+        assert_eq!(listed, vec!["10.9.0.0/16"]);
+
+        let query = "prefixlen=16".parse().unwrap();
+        let listed: Vec<String> = view.list(None, Some(&query), SortOrder::Asc).iter().map(|c| c.cidr.to_string()).collect();
+        assert_eq!(listed, vec!["10.1.0.0/16", "10.9.0.0/16"]);
+
+        let page = Page::of(view.list(None, None, SortOrder::Desc), 1, 1);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.items[0].cidr.to_string(), "10.1.0.0/24");
+    }
+
+    #[test]
+    fn test_utilization_view_follows_events() {
+        let mut view = IpamUtilizationView::default();
+        view.update(&envelope(1, IpamEvent::IpamCreated(IpamCreated {
+            uuid: Uuid::new_v4(), id: String::from("ipam_1"), protocol: IPProtocolFamily::V4, cfg: None })));
+        let mut entries = vec![];
+        for (i, c) in ["10.1.0.0/16", "10.1.0.0/24", "10.1.0.0/26"].iter().enumerate() {
+            let cidr_entry = CidrEntry::from(c.parse::<ipnetwork::IpNetwork>().unwrap());
+            entries.push(cidr_entry.clone());
+            view.update(&envelope(i + 2, IpamEvent::CidrEntryAdded(CidrEntryAdded { cidr_entry })));
+        }
+
+        assert_eq!(view.entries[&entries[0].id].used, 256);
+        assert_eq!(view.entries[&entries[1].id].used, 64);
+        assert_eq!(view.entries[&entries[2].id].used, 0);
+        assert_eq!(view.v4.as_ref().map(|u| (u.total, u.used)), Some((65536, 256)));
+
+        view.update(&envelope(5, IpamEvent::CidrEntryReleased(CidrEntryReleased { cidr_entry: entries[2].clone() })));
+        assert!(!view.entries.contains_key(&entries[2].id));
+        assert_eq!(view.entries[&entries[1].id].used, 0);
+    }
+
+    #[test]
+    fn test_replay_up_to_a_point() {
+        let mut events = vec![(1, IpamEvent::IpamCreated(IpamCreated {
+            uuid: Uuid::new_v4(), id: String::from("ipam_1"), protocol: IPProtocolFamily::V4, cfg: None }))];
+        for (i, c) in ["10.1.0.0/16", "10.2.0.0/16", "10.3.0.0/16"].iter().enumerate() {
+            let cidr_entry = CidrEntry::from(c.parse::<ipnetwork::IpNetwork>().unwrap());
+            events.push((i + 2, IpamEvent::CidrEntryAdded(CidrEntryAdded { cidr_entry })));
+        }
+
+        let view: IpamCidrsView = replay("ipam_1", events.iter().take(3).cloned().collect());
+        assert_eq!(view.ipam.cidrs.len(), 2);
+        let view: IpamCidrsView = replay("ipam_1", events);
+        assert_eq!(view.ipam.cidrs.len(), 3);
+
+        assert_eq!(PointInTime::from_params(None, Some(3)).unwrap(), Some(PointInTime::Sequence(3)));
+        assert!(PointInTime::from_params(Some("2023-05-01T00:00:00Z"), None).unwrap().is_some());
+        assert!(PointInTime::from_params(Some("last tuesday"), None).is_err());
+        assert!(PointInTime::from_params(Some("2023-05-01T00:00:00Z"), Some(3)).is_err());
+        assert_eq!(PointInTime::parse("42").unwrap(), PointInTime::Sequence(42));
+        assert!(matches!(PointInTime::parse("2023-05-01T00:00:00+10:00"), Ok(PointInTime::At(_))));
+    }
+}
+
@@ -0,0 +1,213 @@
+//! Attribute schemas, the rules an Ipam holds its entries' attributes to.
+//!
+//! ```text
+//! {
+//!   "keys": {
+//!     "env":  {"required": true, "values": ["dev", "test", "prod"]},
+//!     "vlan": {"type": "number", "prefix_lens": {"min": 24, "max": 30}},
+//!     "owner": {"pattern": "^[a-z]+@example\\.com$"}
+//!   }
+//! }
+//! ```
+//!
+//! A rule only applies to entries whose prefix length sits inside its
+//! `prefix_lens`; anywhere else the key is not allowed at all.
+//!
+//! Keys are inherited down the tree unless their rule says `"inherit": false`.
+
+use ipnetwork::IpNetwork;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::attributes::{AttributeValue, Attributes};
+use crate::error::IpamError;
+
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AttributeSchema {
+    pub keys: BTreeMap<String, KeyRule>,
+}
+
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct KeyRule {
+    pub required: bool,
+    /// whether entries beneath one holding this key take on its value
+    pub inherit: bool,
+    #[serde(rename = "type")]
+    pub kind: Option<ValueKind>,
+    /// the only values allowed
+    pub values: Option<Vec<AttributeValue>>,
+    /// a regex the value, as text, must match
+    pub pattern: Option<String>,
+    pub prefix_lens: Option<PrefixLens>,
+}
+
+impl Default for KeyRule {
+    fn default() -> Self {
+        KeyRule {
+            required: false,
+            inherit: true,
+            kind: None,
+            values: None,
+            pattern: None,
+            prefix_lens: None,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueKind {
+    String,
+    Number,
+    Bool,
+    List,
+    Map,
+    Ip,
+}
+
+/// An inclusive range of prefix lengths, open at either end
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct PrefixLens {
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+}
+
+impl PrefixLens {
+    fn contains(&self, len: u8) -> bool {
+        self.min.map_or(true, |m| len >= m) && self.max.map_or(true, |m| len <= m)
+    }
+}
+
+/// One way an entry's attributes break the schema
+#[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Violation {
+    pub key: String,
+    pub reason: String,
+}
+
+impl Violation {
+    fn new(key: &str, reason: String) -> Self {
+        Violation { key: String::from(key), reason }
+    }
+}
+
+impl ValueKind {
+    fn of(value: &AttributeValue) -> Self {
+        match value {
+            AttributeValue::String(_) => ValueKind::String,
+            AttributeValue::Number(_) => ValueKind::Number,
+            AttributeValue::Bool(_) => ValueKind::Bool,
+            AttributeValue::List(_) => ValueKind::List,
+            AttributeValue::Map(_) => ValueKind::Map,
+            AttributeValue::Ip(_) => ValueKind::Ip,
+        }
+    }
+}
+
+impl AttributeSchema {
+    /// Whether descendants take on the key, true for any key without a rule
+    pub fn inherits(&self, key: &str) -> bool {
+        self.keys.get(key).map_or(true, |r| r.inherit)
+    }
+
+    /// Refuse a schema that could never be checked, a bad pattern or an empty prefix range
+    pub fn validate(&self) -> Result<(), IpamError> {
+        for (key, rule) in self.keys.iter() {
+            if let Some(p) = &rule.pattern {
+                Regex::new(p).map_err(|e| IpamError::InvalidSchema(format!("{}: bad pattern {}", key, e)))?;
+            }
+            if let Some(PrefixLens { min: Some(min), max: Some(max) }) = rule.prefix_lens {
+                if min > max {
+                    return Err(IpamError::InvalidSchema(format!("{}: prefix_lens min {} is above max {}", key, min, max)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything wrong with the attributes of an entry for `cidr`, in key order
+    pub fn check(&self, cidr: &IpNetwork, attributes: &Attributes) -> Vec<Violation> {
+        let mut violations = vec![];
+        for (key, rule) in self.keys.iter() {
+            let value = attributes.get(key);
+            let applies = rule.prefix_lens.map_or(true, |r| r.contains(cidr.prefix()));
+
+            let value = match value {
+                Some(_) if !applies => {
+                    violations.push(Violation::new(key, format!("does not apply to a /{}", cidr.prefix())));
+                    continue;
+                },
+                None if applies && rule.required => {
+                    violations.push(Violation::new(key, String::from("is required")));
+                    continue;
+                },
+                None => continue,
+                Some(v) => v,
+            };
+
+            if let Some(kind) = rule.kind {
+                if ValueKind::of(value) != kind {
+                    violations.push(Violation::new(key, format!("must be a {:?}", kind).to_lowercase()));
+                }
+            }
+            if let Some(allowed) = &rule.values {
+                if !allowed.contains(value) {
+                    let names: Vec<String> = allowed.iter().map(|v| v.to_string()).collect();
+                    violations.push(Violation::new(key, format!("must be one of {}", names.join(", "))));
+                }
+            }
+            if let Some(pattern) = rule.pattern.as_ref().and_then(|p| Regex::new(p).ok()) {
+                if !pattern.is_match(&value.to_string()) {
+                    violations.push(Violation::new(key, format!("must match {}", pattern)));
+                }
+            }
+        }
+        violations
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::str::FromStr;
+
+    fn schema() -> AttributeSchema {
+        serde_json::from_str(r#"{"keys": {
+            "env":   {"required": true, "values": ["dev", "prod"]},
+            "vlan":  {"type": "number", "prefix_lens": {"min": 24, "max": 30}},
+            "owner": {"pattern": "^[a-z]+@example\\.com$"}
+        }}"#).unwrap()
+    }
+
+    fn check(cidr: &str, attributes: &str) -> Vec<String> {
+        let attributes: Attributes = serde_json::from_str(attributes).unwrap();
+        schema().check(&IpNetwork::from_str(cidr).unwrap(), &attributes)
+            .iter()
+            .map(|v| format!("{} {}", v.key, v.reason))
+            .collect()
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check("10.0.0.0/24", r#"{"env": "dev", "vlan": 10, "owner": "ops@example.com"}"#).is_empty());
+        assert_eq!(check("10.0.0.0/24", r#"{}"#), vec!["env is required"]);
+        assert_eq!(check("10.0.0.0/24", r#"{"env": "qa", "vlan": "10"}"#), vec!["env must be one of dev, prod", "vlan must be a number"]);
+        assert_eq!(check("10.0.0.0/16", r#"{"env": "dev", "vlan": 10}"#), vec!["vlan does not apply to a /16"]);
+        assert_eq!(check("10.0.0.0/24", r#"{"env": "dev", "owner": "Ops@elsewhere"}"#), vec!["owner must match ^[a-z]+@example\\.com$"]);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(schema().validate().is_ok());
+        let bad: AttributeSchema = serde_json::from_str(r#"{"keys": {"a": {"pattern": "("}}}"#).unwrap();
+        assert!(bad.validate().is_err());
+        let bad: AttributeSchema = serde_json::from_str(r#"{"keys": {"a": {"prefix_lens": {"min": 30, "max": 24}}}}"#).unwrap();
+        assert!(bad.validate().is_err());
+    }
+}
@@ -0,0 +1,427 @@
+//! A small query language for locating CidrEntries.
+//!
+//! ```text
+//! within:10.0.0.0/8 AND prefixlen>=24 AND NOT label:env=prod
+//! (sysref:~"^aws::.*" OR orphan:true) contains:10.1.2.3
+//! ```
+//!
+//! Predicates
+//! - `within:<cidr>`      the entry sits inside the network (or is it)
+//! - `contains:<ip|cidr>` the entry covers the address or network
+//! - `prefixlen<op><n>`   with `=`, `!=`, `<`, `<=`, `>`, `>=` (`prefixlen:24` is `=`)
+//! - `label:<key>=<value>` or `label:<key>` to only check the key is present. The key
+//!   can be a dotted path into nested attributes, `label:site.rack=12`. Values are
+//!   read as the attribute's own type, and a list matches when any item does.
+//!   Numbers also take `!=`, `<`, `<=`, `>` and `>=`, `label:vlan>=100`
+//! - `effective:<key>=<value>`, as `label:`, over the attributes inherited from above
+//! - `sysref:<value>` for an exact match, `sysref:~<regex>` for a pattern
+//! - `parent:<id>`        the entry's parent id
+//! - `orphan:true|false`  whether the entry has no parent
+//! - a bare word is the substring match of `Ipam::filter`
+//!
+//! Terms are combined with `AND`, `OR` and `NOT` (NOT binds tightest, then AND),
+//! and grouped with parentheses. Terms side by side are ANDed. Double quotes
+//! keep spaces and parentheses inside a term.
+
+use ipnetwork::IpNetwork;
+use regex::Regex;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::attributes::Attributes;
+use crate::error::IpamError;
+use crate::ipam_model::{prefix_key, CidrEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cmp {
+    fn test<T: PartialOrd>(self, left: T, right: T) -> bool {
+        match self {
+            Cmp::Eq => left == right,
+            Cmp::Ne => left != right,
+            Cmp::Lt => left < right,
+            Cmp::Le => left <= right,
+            Cmp::Gt => left > right,
+            Cmp::Ge => left >= right,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Predicate {
+    Within(IpNetwork),
+    Contains(IpNetwork),
+    PrefixLen(Cmp, u8),
+    Label { key: String, test: Option<(Cmp, String)> },
+    Effective { key: String, test: Option<(Cmp, String)> },
+    Sysref(String),
+    SysrefMatches(Regex),
+    Parent(String),
+    Orphan(bool),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum SearchExpr {
+    And(Box<SearchExpr>, Box<SearchExpr>),
+    Or(Box<SearchExpr>, Box<SearchExpr>),
+    Not(Box<SearchExpr>),
+    Is(Predicate),
+}
+
+impl FromStr for SearchExpr {
+    type Err = IpamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or_expr()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(t) => Err(invalid(format!("unexpected '{}'", t))),
+        }
+    }
+}
+
+impl SearchExpr {
+    /// Whether the entry matches. `effective` are the entry's attributes merged with
+    /// those it inherits, and only needed when `needs_effective` says so.
+    pub fn matches(&self, entry: &CidrEntry, effective: Option<&Attributes>) -> bool {
+        match self {
+            SearchExpr::And(l, r) => l.matches(entry, effective) && r.matches(entry, effective),
+            SearchExpr::Or(l, r) => l.matches(entry, effective) || r.matches(entry, effective),
+            SearchExpr::Not(e) => !e.matches(entry, effective),
+            SearchExpr::Is(p) => p.matches(entry, effective),
+        }
+    }
+
+    pub fn needs_effective(&self) -> bool {
+        match self {
+            SearchExpr::And(l, r) | SearchExpr::Or(l, r) => l.needs_effective() || r.needs_effective(),
+            SearchExpr::Not(e) => e.needs_effective(),
+            SearchExpr::Is(p) => matches!(p, Predicate::Effective { .. }),
+        }
+    }
+}
+
+fn label_matches(attributes: &Attributes, key: &str, test: &Option<(Cmp, String)>) -> bool {
+    match (attributes.lookup(key), test) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(v), Some((Cmp::Eq, text))) => v.matches(text),
+        (Some(v), Some((Cmp::Ne, text))) => !v.matches(text),
+        (Some(v), Some((cmp, text))) => match (v.as_f64(), text.parse::<f64>()) {
+            (Some(left), Ok(right)) => cmp.test(left, right),
+            _ => false,
+        },
+    }
+}
+
+impl Predicate {
+    fn matches(&self, entry: &CidrEntry, effective: Option<&Attributes>) -> bool {
+        match self {
+            Predicate::Within(net) => {
+                same_family(net, &entry.cidr) && prefix_key(net).contains(&prefix_key(&entry.cidr))
+            }
+            Predicate::Contains(net) => {
+                same_family(net, &entry.cidr) && prefix_key(&entry.cidr).contains(&prefix_key(net))
+            }
+            Predicate::PrefixLen(cmp, len) => cmp.test(entry.cidr.prefix(), *len),
+            Predicate::Label { key, test } => label_matches(&entry.attributes, key, test),
+            Predicate::Effective { key, test } => label_matches(effective.unwrap_or(&entry.attributes), key, test),
+            Predicate::Sysref(s) => entry.sysref.as_deref() == Some(s.as_str()),
+            Predicate::SysrefMatches(re) => entry.sysref.as_ref().map_or(false, |s| re.is_match(s)),
+            Predicate::Parent(id) => entry.parent.as_ref().map_or(false, |p| **p == *id),
+            Predicate::Orphan(orphan) => entry.parent.is_none() == *orphan,
+            Predicate::Text(search) => {
+                entry.cidr.to_string().contains(search.as_str())
+                    || entry.id.contains(search.as_str())
+                    || entry.uuid.to_string().contains(search.as_str())
+                    || entry.sysref.as_ref().map_or(false, |r| r.contains(search.as_str()))
+            }
+        }
+    }
+}
+
+fn same_family(a: &IpNetwork, b: &IpNetwork) -> bool {
+    a.is_ipv4() == b.is_ipv4()
+}
+
+fn invalid(msg: String) -> IpamError {
+    IpamError::InvalidQuery(msg)
+}
+
+/* --- Parsing -----------------------------------------*/
+
+/// Splits on whitespace, with parentheses as tokens of their own.
+/// Double quotes are dropped, keeping whatever they wrap inside the token.
+fn tokenize(s: &str) -> Result<Vec<String>, IpamError> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if quoted => current.push(c),
+            '(' | ')' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push(c.to_string());
+            }
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid(String::from("unterminated quote")));
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        self.peek().map_or(false, |t| t.eq_ignore_ascii_case(keyword))
+    }
+
+    fn or_expr(&mut self) -> Result<SearchExpr, IpamError> {
+        let mut left = self.and_expr()?;
+        while self.peek_keyword("OR") {
+            self.next();
+            let right = self.and_expr()?;
+            left = SearchExpr::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and_expr(&mut self) -> Result<SearchExpr, IpamError> {
+        let mut left = self.unary()?;
+        loop {
+            if self.peek_keyword("AND") {
+                self.next();
+            } else if self.peek().is_none() || self.peek() == Some(")") || self.peek_keyword("OR") {
+                break;
+            }
+            let right = self.unary()?;
+            left = SearchExpr::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<SearchExpr, IpamError> {
+        if self.peek_keyword("NOT") {
+            self.next();
+            return Ok(SearchExpr::Not(Box::new(self.unary()?)));
+        }
+        match self.next() {
+            None => Err(invalid(String::from("unexpected end of query"))),
+            Some(t) if t == "(" => {
+                let inner = self.or_expr()?;
+                match self.next() {
+                    Some(t) if t == ")" => Ok(inner),
+                    _ => Err(invalid(String::from("missing ')'"))),
+                }
+            }
+            Some(t) if t == ")" || t.eq_ignore_ascii_case("AND") || t.eq_ignore_ascii_case("OR") => {
+                Err(invalid(format!("unexpected '{}'", t)))
+            }
+            Some(t) => Ok(SearchExpr::Is(predicate(&t)?)),
+        }
+    }
+}
+
+fn predicate(term: &str) -> Result<Predicate, IpamError> {
+    if let Some(rest) = term.strip_prefix("prefixlen") {
+        return prefix_len(rest).ok_or_else(|| invalid(format!("bad prefix length test '{}'", term)));
+    }
+
+    let (name, arg) = match term.find(':') {
+        Some(i) => (&term[..i], &term[i + 1..]),
+        None => return Ok(Predicate::Text(String::from(term))),
+    };
+
+    match name {
+        "within" => Ok(Predicate::Within(network(arg)?)),
+        "contains" => Ok(Predicate::Contains(network(arg)?)),
+        "label" => Ok(label(arg)),
+        "effective" => Ok(match label(arg) {
+            Predicate::Label { key, test } => Predicate::Effective { key, test },
+            other => other,
+        }),
+        "sysref" => match arg.strip_prefix('~') {
+            Some(pattern) => Regex::new(pattern)
+                .map(Predicate::SysrefMatches)
+                .map_err(|e| invalid(format!("bad sysref pattern {}", e))),
+            None => Ok(Predicate::Sysref(String::from(arg))),
+        },
+        "parent" => Ok(Predicate::Parent(String::from(arg))),
+        "orphan" => arg
+            .parse::<bool>()
+            .map(Predicate::Orphan)
+            .map_err(|_| invalid(format!("orphan is true or false, not '{}'", arg))),
+        // a cidr (v6 especially) has colons of its own, so treat it as plain text
+        _ if IpNetwork::from_str(term).is_ok() || IpAddr::from_str(term).is_ok() => Ok(Predicate::Text(String::from(term))),
+        _ => Err(invalid(format!("unknown predicate '{}'", name))),
+    }
+}
+
+const OPS: [(&str, Cmp); 6] = [(">=", Cmp::Ge), ("<=", Cmp::Le), ("!=", Cmp::Ne), (">", Cmp::Gt), ("<", Cmp::Lt), ("=", Cmp::Eq)];
+
+/// `key`, or `key` followed by a comparison, splitting on the first operator
+fn label(arg: &str) -> Predicate {
+    let split = arg.char_indices()
+        .filter(|(_, c)| "=!<>".contains(*c))
+        .find_map(|(i, _)| OPS.iter().find(|(op, _)| arg[i..].starts_with(op)).map(|(op, cmp)| (i, op, cmp)));
+    match split {
+        Some((i, op, cmp)) => Predicate::Label { key: String::from(&arg[..i]), test: Some((*cmp, String::from(&arg[i + op.len()..]))) },
+        None => Predicate::Label { key: String::from(arg), test: None },
+    }
+}
+
+fn prefix_len(rest: &str) -> Option<Predicate> {
+    OPS.iter().chain([(":", Cmp::Eq)].iter()).find_map(|(op, cmp)| {
+        rest.strip_prefix(op)
+            .and_then(|n| n.parse::<u8>().ok())
+            .map(|n| Predicate::PrefixLen(*cmp, n))
+    })
+}
+
+/// A network, or a lone address as a host network
+fn network(arg: &str) -> Result<IpNetwork, IpamError> {
+    IpNetwork::from_str(arg)
+        .or_else(|_| IpAddr::from_str(arg).map(IpNetwork::from))
+        .map_err(|_| invalid(format!("'{}' is not an address or cidr", arg)))
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ipam_model::Label;
+    use std::convert::TryFrom;
+
+    fn entry(cidr: &str, sysref: Option<&str>, labels: &[(&str, &str)]) -> CidrEntry {
+        let mut e = CidrEntry::try_from(cidr).unwrap();
+        e.sysref = sysref.map(String::from);
+        e.attributes = labels.iter().map(|(k, v)| Label::new(k, v)).collect();
+        e
+    }
+
+    fn matches(query: &str, e: &CidrEntry) -> bool {
+        query.parse::<SearchExpr>().expect("query should parse").matches(e, None)
+    }
+
+    #[test]
+    fn test_predicates() {
+        let e = entry("10.1.2.0/24", Some("aws::vpc-1234"), &[("env", "prod")]);
+
+        assert!(matches("within:10.0.0.0/8", &e));
+        assert!(matches("within:10.1.2.0/24", &e));
+        assert!(!matches("within:10.1.2.0/25", &e));
+        assert!(!matches("within:::/0", &e));
+        assert!(matches("contains:10.1.2.3", &e));
+        assert!(!matches("contains:10.1.3.3", &e));
+        assert!(matches("prefixlen>=24", &e));
+        assert!(!matches("prefixlen>24", &e));
+        assert!(matches("prefixlen:24", &e));
+        assert!(matches("label:env=prod", &e));
+        assert!(matches("label:env", &e));
+        assert!(!matches("label:env=dev", &e));
+        assert!(matches("sysref:~^aws::vpc-[0-9]+$", &e));
+        assert!(!matches("sysref:aws", &e));
+        assert!(matches("orphan:true", &e));
+        assert!(matches("vpc-12", &e));
+        assert!(matches("10.1.2.0/24", &e));
+    }
+
+    #[test]
+    fn test_boolean_combinations() {
+        let prod = entry("10.1.2.0/24", None, &[("env", "prod")]);
+        let dev = entry("10.1.3.0/24", None, &[("env", "dev")]);
+
+        let q = "within:10.0.0.0/8 AND NOT label:env=prod";
+        assert!(!matches(q, &prod));
+        assert!(matches(q, &dev));
+
+        // AND binds tighter than OR
+        let q = "label:env=dev OR label:env=prod prefixlen<24";
+        assert!(matches(q, &dev));
+        assert!(!matches(q, &prod));
+
+        let q = "(label:env=dev OR label:env=prod) prefixlen<24";
+        assert!(!matches(q, &dev));
+
+        // missing sysrefs never match, and never panic
+        assert!(!matches("sysref:~\"^(a|b)$\"", &dev));
+    }
+
+    #[test]
+    fn test_typed_labels() {
+        let mut e = entry("10.1.2.0/24", None, &[]);
+        e.attributes = serde_json::from_str(r#"{
+            "vlan": 120, "routed": true, "dns": ["10.0.0.2", "10.0.0.3"], "site": {"rack": 12, "region": "syd"}
+        }"#).unwrap();
+
+        assert!(matches("label:vlan=120", &e));
+        assert!(matches("label:vlan>=100 label:vlan<200", &e));
+        assert!(!matches("label:vlan>120", &e));
+        assert!(matches("label:vlan!=7", &e));
+        assert!(matches("label:routed=true", &e));
+        assert!(matches("label:dns=10.0.0.3", &e));
+        assert!(matches("label:site.rack=12 label:site.region=syd", &e));
+        assert!(matches("label:site", &e));
+        assert!(!matches("label:site.row", &e));
+        assert!(!matches("label:site.region>3", &e));
+    }
+
+    #[test]
+    fn test_effective_labels() {
+        let e = entry("10.1.2.0/24", None, &[("env", "dev")]);
+        let effective = vec![Label::new("env", "dev"), Label::new("site", "syd1")].into_iter().collect();
+        let q: SearchExpr = "effective:site=syd1".parse().unwrap();
+
+        assert!(q.needs_effective());
+        assert!(q.matches(&e, Some(&effective)));
+        assert!(!q.matches(&e, None));
+        assert!(!"label:site=syd1".parse::<SearchExpr>().unwrap().needs_effective());
+    }
+
+    #[test]
+    fn test_bad_queries() {
+        for q in ["", "within:banana", "prefixlen>>3", "(label:a", "label:a OR", "nope:1", "orphan:maybe", "sysref:~("].iter() {
+            assert!(q.parse::<SearchExpr>().is_err(), "{} should fail", q);
+        }
+    }
+}
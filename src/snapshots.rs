@@ -0,0 +1,237 @@
+//! Snapshots of the Ipam aggregate, so a command replays only the events
+//! committed since the latest snapshot rather than every event the Ipam has.
+//!
+//! `SnapshotStore` is the event store over whichever backend is configured.
+//! It loads an aggregate from its snapshot plus the newer events, and takes
+//! a fresh snapshot each time the event count passes a multiple of `every`.
+//! Only the latest snapshot of each Ipam is kept.
+//!
+//! `ESIPAM_SNAPSHOT_EVERY` sets `every`, 500 when it is not set.
+
+use chrono::Utc;
+use cqrs_es::{Aggregate, AggregateContext, AggregateError, DomainEvent, EventEnvelope, EventStore};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::backend::{technical, Backend, StoredEvent};
+use crate::error::IpamError;
+use crate::events::IpamEvent;
+use crate::ipam_model::Ipam;
+
+/// How many events go by between snapshots, unless told otherwise
+pub const DEFAULT_SNAPSHOT_EVERY: usize = 500;
+
+/// From `ESIPAM_SNAPSHOT_EVERY`, `DEFAULT_SNAPSHOT_EVERY` when it is not set
+pub fn every_from_env() -> Result<usize, IpamError> {
+    match std::env::var("ESIPAM_SNAPSHOT_EVERY") {
+        Ok(s)  => s.parse().map_err(|_| IpamError::BadRequest(format!("{} is not a number of events", s))),
+        Err(_) => Ok(DEFAULT_SNAPSHOT_EVERY),
+    }
+}
+
+pub struct SnapshotStore {
+    backend: Arc<dyn Backend>,
+    every: usize,
+}
+
+impl SnapshotStore {
+    pub fn new(backend: Arc<dyn Backend>, every: usize) -> Self {
+        SnapshotStore { backend, every: every.max(1) }
+    }
+
+    /// The latest snapshot of the aggregate, with the sequence number of the last event in it.
+    /// One that can not be read is reported and passed over, for a replay of every event.
+    fn latest(&self, aggregate_id: &str) -> Option<(usize, Ipam)> {
+        let loaded = self.backend.snapshot(Ipam::aggregate_type(), aggregate_id)
+            .and_then(|snapshot| snapshot.map(|(sequence, payload)| {
+                serde_json::from_value(payload).map(|ipam| (sequence, ipam)).map_err(technical)
+            }).transpose());
+        match loaded {
+            Ok(latest) => latest,
+            Err(e) => {
+                println!("<snapshot_load_failed> {} {:?}", aggregate_id, e);
+                None
+            }
+        }
+    }
+
+    /// The aggregate's events after `sequence`, in order
+    fn events_after(&self, aggregate_id: &str, sequence: usize) -> Result<Vec<(usize, IpamEvent)>, AggregateError> {
+        self.backend.events(Ipam::aggregate_type(), aggregate_id, sequence)?
+            .into_iter()
+            .map(|e| Ok((e.sequence, serde_json::from_value(e.payload).map_err(technical)?)))
+            .collect()
+    }
+
+    /// The aggregate built up from `ipam`, as it stood after event `sequence`
+    fn replayed(&self, aggregate_id: &str, sequence: usize, mut ipam: Ipam) -> Result<AggregateContext<Ipam>, AggregateError> {
+        let newer = self.events_after(aggregate_id, sequence)?;
+        let current_sequence = newer.last().map_or(sequence, |(s, _)| *s);
+        for (_, event) in newer {
+            event.apply(&mut ipam);
+        }
+        Ok(AggregateContext { aggregate_id: String::from(aggregate_id), aggregate: ipam, current_sequence })
+    }
+
+    /// Store `ipam`, as it stands after event `sequence`, as the aggregate's snapshot
+    pub fn save(&self, aggregate_id: &str, sequence: usize, ipam: &Ipam) -> Result<(), AggregateError> {
+        let payload = serde_json::to_value(ipam).map_err(technical)?;
+        self.backend.save_snapshot(Ipam::aggregate_type(), aggregate_id, sequence, &payload)
+    }
+
+    /// Take a snapshot of the aggregate as it stands now, or 0 without saving
+    /// anything when it has no events
+    pub fn snapshot(&self, aggregate_id: &str) -> Result<usize, AggregateError> {
+        let context = self.load_aggregate(aggregate_id);
+        if context.current_sequence == 0 {
+            return Ok(0);
+        }
+        self.save(aggregate_id, context.current_sequence, &context.aggregate)?;
+        Ok(context.current_sequence)
+    }
+
+    /// Throw away the aggregate's snapshot and take a new one from a full replay,
+    /// for when a snapshot no longer matches what its events build
+    pub fn rebuild(&self, aggregate_id: &str) -> Result<usize, AggregateError> {
+        let context = self.replayed(aggregate_id, 0, Ipam::default())?;
+        if context.current_sequence == 0 {
+            return Ok(0);
+        }
+        self.save(aggregate_id, context.current_sequence, &context.aggregate)?;
+        Ok(context.current_sequence)
+    }
+
+    /// Every Ipam with events, to rebuild them all
+    pub fn aggregate_ids(&self) -> Result<Vec<String>, AggregateError> {
+        self.backend.aggregate_ids(Ipam::aggregate_type())
+    }
+}
+
+/// Whether going from `before` to `after` events passes a multiple of `every`
+fn due(before: usize, after: usize, every: usize) -> bool {
+    after / every > before / every
+}
+
+impl EventStore<Ipam, IpamEvent> for SnapshotStore {
+    fn load(&self, aggregate_id: &str) -> Vec<EventEnvelope<Ipam, IpamEvent>> {
+        let loaded = self.backend.events(Ipam::aggregate_type(), aggregate_id, 0).and_then(|events| events.into_iter()
+            .map(|e| Ok(EventEnvelope::new_with_metadata(String::from(aggregate_id), e.sequence, String::from(Ipam::aggregate_type()),
+                serde_json::from_value(e.payload).map_err(technical)?, e.metadata)))
+            .collect::<Result<Vec<_>, AggregateError>>());
+        // the trait has no room for an error, so none of the events rather than a history with holes in it
+        match loaded {
+            Ok(events) => events,
+            Err(e) => {
+                println!("<event_load_failed> {} {:?}", aggregate_id, e);
+                vec![]
+            }
+        }
+    }
+
+    fn load_aggregate(&self, aggregate_id: &str) -> AggregateContext<Ipam> {
+        let from_snapshot = self.latest(aggregate_id)
+            .map(|(sequence, ipam)| self.replayed(aggregate_id, sequence, ipam));
+        match from_snapshot {
+            Some(Ok(context)) => context,
+            _ => self.replayed(aggregate_id, 0, Ipam::default()).unwrap_or_else(|e| {
+                println!("<event_load_failed> {} {:?}", aggregate_id, e);
+                AggregateContext { aggregate_id: String::from(aggregate_id), aggregate: Ipam::default(), current_sequence: 0 }
+            }),
+        }
+    }
+
+    fn commit(&self, events: Vec<IpamEvent>, context: AggregateContext<Ipam>, metadata: HashMap<String, String>)
+        -> Result<Vec<EventEnvelope<Ipam, IpamEvent>>, AggregateError>
+    {
+        let AggregateContext { aggregate_id, mut aggregate, current_sequence: before } = context;
+        let timestamp = Utc::now();
+        let stored = events.iter().enumerate()
+            .map(|(i, event)| Ok(StoredEvent {
+                sequence: before + i + 1,
+                payload: serde_json::to_value(event).map_err(technical)?,
+                metadata: metadata.clone(),
+                timestamp,
+            }))
+            .collect::<Result<Vec<StoredEvent>, AggregateError>>()?;
+        self.backend.append(Ipam::aggregate_type(), &aggregate_id, before, &stored)?;
+
+        let committed: Vec<EventEnvelope<Ipam, IpamEvent>> = events.into_iter().zip(stored.iter())
+            .map(|(event, s)| EventEnvelope::new_with_metadata(aggregate_id.clone(), s.sequence, String::from(Ipam::aggregate_type()), event, metadata.clone()))
+            .collect();
+
+        let after = before + committed.len();
+        if due(before, after, self.every) {
+            for envelope in committed.iter() {
+                envelope.payload.clone().apply(&mut aggregate);
+            }
+            // the events are in, a missed snapshot only costs a longer replay next time
+            if let Err(e) = self.save(&aggregate_id, after, &aggregate) {
+                println!("<snapshot_save_failed> {} {:?}", aggregate_id, e);
+            }
+        }
+        Ok(committed)
+    }
+}
+
+/* --- Tests -----------------------------------------*/
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use cqrs_es::Command;
+    use uuid::Uuid;
+    use crate::commands::{AddCidrEntry, CreateNewIpam};
+    use crate::file_backend::FileBackend;
+
+    fn execute<C: Command<Ipam, IpamEvent>>(store: &SnapshotStore, command: C) {
+        let context = store.load_aggregate("ipam_1");
+        let events = command.handle(&context.aggregate).unwrap();
+        store.commit(events, context, HashMap::new()).unwrap();
+    }
+
+    #[test]
+    fn test_load_from_snapshot_plus_newer_events() {
+        let path = std::env::temp_dir().join(format!("esipam-{}.db", Uuid::new_v4()));
+        let store = SnapshotStore::new(Arc::new(FileBackend::open(&path).unwrap()), 2);
+        assert_eq!(store.snapshot("ipam_1").unwrap(), 0);
+        assert!(store.backend.snapshot("Ipam", "ipam_1").unwrap().is_none());
+
+        execute(&store, CreateNewIpam { id: String::from("ipam_1"), ..Default::default() });
+        execute(&store, AddCidrEntry { cidr: String::from("10.0.0.0/8"), ..Default::default() });
+        assert_eq!(store.backend.snapshot("Ipam", "ipam_1").unwrap().map(|(s, _)| s), Some(2));
+        execute(&store, AddCidrEntry { cidr: String::from("10.1.0.0/16"), ..Default::default() });
+
+        // a snapshot only the loaded aggregate can have come from
+        let (_, payload) = store.backend.snapshot("Ipam", "ipam_1").unwrap().unwrap();
+        let mut marked: Ipam = serde_json::from_value(payload).unwrap();
+        marked.id = String::from("from_snapshot");
+        store.save("ipam_1", 2, &marked).unwrap();
+
+        let context = store.load_aggregate("ipam_1");
+        assert_eq!(context.current_sequence, 3);
+        assert_eq!(context.aggregate.id, "from_snapshot");
+        assert_eq!(context.aggregate.cidrs.len(), 2);
+
+        // a full replay goes past the snapshot
+        assert_eq!(store.rebuild("ipam_1").unwrap(), 3);
+        let context = store.load_aggregate("ipam_1");
+        assert_eq!(context.aggregate.id, "ipam_1");
+        assert_eq!(context.aggregate.cidrs.len(), 2);
+
+        // as is a snapshot that no longer reads as an Ipam
+        store.backend.save_snapshot("Ipam", "ipam_1", 3, &serde_json::json!({ "cidrs": "garbled" })).unwrap();
+        assert!(store.latest("ipam_1").is_none());
+        assert_eq!(store.load_aggregate("ipam_1").current_sequence, 3);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(crate::file_backend::lock_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_is_due_on_passing_a_multiple() {
+        assert!(!due(0, 99, 100));
+        assert!(due(99, 100, 100));
+        assert!(due(98, 103, 100));
+        assert!(!due(100, 199, 100));
+        assert!(due(0, 1, 1));
+    }
+}